@@ -1,162 +1,585 @@
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io;
+use std::ops::RangeInclusive;
 use std::time::Instant;
 
-fn parse_topographic_map(puzzle: &str) -> Vec<Vec<u8>> {
-    let map = puzzle
+type Position = (usize, usize);
+
+/// Heights of the map, `None` for the impassable `.` cells of the puzzle's examples
+struct TopographicMap {
+    heights: Vec<Vec<Option<u8>>>,
+}
+
+fn parse_topographic_map(puzzle: &str) -> TopographicMap {
+    let heights = puzzle
         .lines()
         .map(|line| {
             line.chars()
-                .map(|height| height.to_digit(10).unwrap() as u8)
+                .map(|height| match height {
+                    '.' => None,
+                    _ => Some(height.to_digit(10).expect("Invalid height in map") as u8),
+                })
                 .collect()
         })
         .collect();
 
-    map
+    TopographicMap { heights }
 }
 
-fn calculate_trailhead_score(map: &Vec<Vec<u8>>, start_row: usize, start_col: usize) -> usize {
-    let mut visited = vec![vec![false; map[0].len()]; map.len()];
-    let mut queue = VecDeque::new();
-    queue.push_back((start_row, start_col, 0));
-    visited[start_row][start_col] = true;
+/// What counts as a hiking trail
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ClimbingRules {
+    /// Allowed height change of a single step, either all positive or all negative
+    deltas: RangeInclusive<i8>,
+    start: u8,
+    end: u8,
+    diagonal: bool,
+}
 
-    let mut reachable_nines = 0;
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RulesError {
+    /// The delta range holds no value at all
+    EmptyDeltas,
+    /// The delta range holds zero or both climbing and descending steps, so trails could
+    /// run in circles
+    MixedDeltas,
+    /// The deltas lead away from the end height, or start and end are the same
+    WrongDirection { start: u8, end: u8 },
+}
 
-    while let Some((row, col, height)) = queue.pop_front() {
-        if height == 9 {
-            reachable_nines += 1;
-            continue;
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RulesError::EmptyDeltas => write!(f, "the height delta range is empty"),
+            RulesError::MixedDeltas => {
+                write!(f, "height deltas must be all positive or all negative")
+            }
+            RulesError::WrongDirection { start, end } => write!(
+                f,
+                "trails with these deltas cannot get from height {} to height {}",
+                start, end
+            ),
         }
+    }
+}
+
+impl std::error::Error for RulesError {}
 
-        let valid_neighbors = [(0, 1), (1, 0), (0, -1), (-1, 0)]
+impl From<RulesError> for io::Error {
+    fn from(error: RulesError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+impl Default for ClimbingRules {
+    /// The puzzle's rules: from 0 to 9, up by exactly 1, no diagonal steps
+    fn default() -> Self {
+        ClimbingRules {
+            deltas: 1..=1,
+            start: 0,
+            end: 9,
+            diagonal: false,
+        }
+    }
+}
+
+impl ClimbingRules {
+    fn new(
+        deltas: RangeInclusive<i8>,
+        start: u8,
+        end: u8,
+        diagonal: bool,
+    ) -> Result<Self, RulesError> {
+        if deltas.is_empty() {
+            return Err(RulesError::EmptyDeltas);
+        }
+        // Trails must keep climbing or keep descending, so they can never run in circles
+        if *deltas.start() <= 0 && *deltas.end() >= 0 {
+            return Err(RulesError::MixedDeltas);
+        }
+        if (end > start) != (*deltas.start() > 0) || end == start {
+            return Err(RulesError::WrongDirection { start, end });
+        }
+
+        Ok(ClimbingRules {
+            deltas,
+            start,
+            end,
+            diagonal,
+        })
+    }
+
+    fn is_climbing(&self) -> bool {
+        *self.deltas.start() > 0
+    }
+}
+
+impl TopographicMap {
+    fn height(&self, (row, col): Position) -> Option<u8> {
+        self.heights[row][col]
+    }
+
+    fn neighbors(
+        &self,
+        (row, col): Position,
+        diagonal: bool,
+    ) -> impl Iterator<Item = Position> + '_ {
+        let directions: &[(isize, isize)] = if diagonal {
+            &[
+                (0, 1),
+                (1, 1),
+                (1, 0),
+                (1, -1),
+                (0, -1),
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+            ]
+        } else {
+            &[(0, 1), (1, 0), (0, -1), (-1, 0)]
+        };
+
+        directions
             .iter()
-            .map(|&(dr, dc)| (row as isize + dr, col as isize + dc))
+            .map(move |&(dr, dc)| (row as isize + dr, col as isize + dc))
             .filter(|&(new_row, new_col)| {
                 new_row >= 0
                     && new_col >= 0
-                    && (new_row as usize) < map.len()
-                    && (new_col as usize) < map[0].len()
+                    && (new_row as usize) < self.heights.len()
+                    && (new_col as usize) < self.heights[0].len()
             })
             .map(|(new_row, new_col)| (new_row as usize, new_col as usize))
-            .filter(|&(new_row, new_col)| {
-                !visited[new_row][new_col] && map[new_row][new_col] == height + 1
+    }
+
+    /// Cells a hiking trail can continue to from the given cell, none once it reached the end
+    fn steps<'a>(
+        &'a self,
+        rules: &'a ClimbingRules,
+        position: Position,
+    ) -> impl Iterator<Item = Position> + 'a {
+        let height = self.height(position).filter(|&height| height != rules.end);
+        self.neighbors(position, rules.diagonal)
+            .filter(move |&next| match (height, self.height(next)) {
+                (Some(height), Some(next)) => rules.deltas.contains(&(next as i8 - height as i8)),
+                _ => false,
             })
-            .collect::<Vec<(usize, usize)>>();
+    }
 
-        for (new_row, new_col) in valid_neighbors {
-            visited[new_row][new_col] = true;
-            queue.push_back((new_row, new_col, height + 1));
-        }
+    fn cells(&self) -> impl Iterator<Item = Position> + '_ {
+        self.heights
+            .iter()
+            .enumerate()
+            .flat_map(|(row, heights)| (0..heights.len()).map(move |col| (row, col)))
     }
 
-    reachable_nines
+    fn trailheads<'a>(&'a self, rules: &'a ClimbingRules) -> impl Iterator<Item = Position> + 'a {
+        self.cells()
+            .filter(move |&position| self.height(position) == Some(rules.start))
+    }
 }
 
-fn calculate_trailhead_rating(map: &Vec<Vec<u8>>, start_row: usize, start_col: usize) -> usize {
-    let mut distinct_trails = 0;
+/// For every cell, the summits reachable from it and the number of distinct trails to them
+struct TrailAnalysis {
+    summits: Vec<Vec<HashSet<Position>>>,
+    trails: Vec<Vec<usize>>,
+}
 
-    fn dfs(
-        map: &Vec<Vec<u8>>,
-        row: usize,
-        col: usize,
-        current_height: u8,
-        visited: &mut Vec<Vec<bool>>,
-        trails_count: &mut usize,
-    ) {
-        if current_height == 9 {
-            *trails_count += 1;
-            return;
+impl TrailAnalysis {
+    /// Single pass over the cells from the end height back towards the start: a cell
+    /// reaches whatever the cells it can step to reach, and its trails are the sum of theirs
+    fn new(map: &TopographicMap, rules: &ClimbingRules) -> Self {
+        let mut by_height = vec![Vec::new(); 10];
+        for position in map.cells() {
+            if let Some(height) = map.height(position) {
+                by_height[height as usize].push(position);
+            }
+        }
+        if rules.is_climbing() {
+            by_height.reverse();
         }
 
-        visited[row][col] = true;
-
-        let directions = [(0, 1), (1, 0), (0, -1), (-1, 0)];
-
-        for &(dr, dc) in &directions {
-            let new_row = row as isize + dr;
-            let new_col = col as isize + dc;
-
-            if new_row >= 0
-                && new_col >= 0
-                && (new_row as usize) < map.len()
-                && (new_col as usize) < map[0].len()
-            {
-                let new_row = new_row as usize;
-                let new_col = new_col as usize;
-
-                if !visited[new_row][new_col] && map[new_row][new_col] == current_height + 1 {
-                    dfs(
-                        map,
-                        new_row,
-                        new_col,
-                        current_height + 1,
-                        visited,
-                        trails_count,
-                    );
+        let (rows, cols) = (map.heights.len(), map.heights[0].len());
+        let mut summits = vec![vec![HashSet::new(); cols]; rows];
+        let mut trails = vec![vec![0; cols]; rows];
+        for cells in by_height {
+            for (row, col) in cells {
+                if map.height((row, col)) == Some(rules.end) {
+                    summits[row][col].insert((row, col));
+                    trails[row][col] = 1;
+                    continue;
+                }
+
+                let mut reachable = HashSet::new();
+                let mut count = 0;
+                for (next_row, next_col) in map.steps(rules, (row, col)) {
+                    reachable.extend(&summits[next_row][next_col]);
+                    count += trails[next_row][next_col];
                 }
+                summits[row][col] = reachable;
+                trails[row][col] = count;
             }
         }
 
-        visited[row][col] = false; // Unmark the cell for other paths
+        TrailAnalysis { summits, trails }
     }
 
-    let mut visited = vec![vec![false; map[0].len()]; map.len()];
-    dfs(
-        map,
-        start_row,
-        start_col,
-        0,
-        &mut visited,
-        &mut distinct_trails,
-    );
+    /// Number of summits reachable from the cell
+    fn score(&self, (row, col): Position) -> usize {
+        self.summits[row][col].len()
+    }
 
-    distinct_trails
+    /// Number of distinct trails from the cell to any summit
+    fn rating(&self, (row, col): Position) -> usize {
+        self.trails[row][col]
+    }
 }
 
-fn calculate_total_trailhead_scores(map: &Vec<Vec<u8>>) -> usize {
-    map.iter()
-        .enumerate()
-        .flat_map(|(row_idx, row)| {
-            row.iter().enumerate().map(move |(col_idx, &height)| {
-                if height == 0 {
-                    calculate_trailhead_score(map, row_idx, col_idx)
-                } else {
-                    0
+/// Lazily walks every hiking trail from a cell, depth first, yielding each as the list of
+/// its cells from the start up to the summit
+struct Trails<'a> {
+    map: &'a TopographicMap,
+    rules: &'a ClimbingRules,
+    path: Vec<Position>,
+    /// Steps of every cell on the path that are still to be tried
+    pending: Vec<Vec<Position>>,
+}
+
+impl<'a> Trails<'a> {
+    fn new(map: &'a TopographicMap, rules: &'a ClimbingRules, start: Position) -> Self {
+        Trails {
+            map,
+            rules,
+            path: vec![start],
+            pending: vec![map.steps(rules, start).collect()],
+        }
+    }
+}
+
+impl Iterator for Trails<'_> {
+    type Item = Vec<Position>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let &position = self.path.last()?;
+            if self.map.height(position) == Some(self.rules.end) {
+                let trail = self.path.clone();
+                self.path.pop();
+                self.pending.pop();
+                return Some(trail);
+            }
+
+            match self.pending.last_mut()?.pop() {
+                Some(next) => {
+                    self.path.push(next);
+                    self.pending
+                        .push(self.map.steps(self.rules, next).collect());
                 }
-            })
-        })
+                None => {
+                    self.path.pop();
+                    self.pending.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Graphviz DOT of every step that is part of some trail, trailheads green and summits red
+fn export_trail_graph(
+    map: &TopographicMap,
+    rules: &ClimbingRules,
+    analysis: &TrailAnalysis,
+) -> String {
+    let on_trail = |position: Position| analysis.rating(position) > 0;
+
+    // Only cells reachable from a trailhead, walking steps that still lead to a summit
+    let mut queue = map
+        .trailheads(rules)
+        .filter(|&position| on_trail(position))
+        .collect::<VecDeque<_>>();
+    let mut seen = queue.iter().copied().collect::<HashSet<_>>();
+
+    let mut nodes = Vec::new();
+    let mut edges = Vec::new();
+    while let Some(position) = queue.pop_front() {
+        nodes.push(position);
+        for next in map.steps(rules, position).filter(|&next| on_trail(next)) {
+            edges.push((position, next));
+            if seen.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+    nodes.sort_unstable();
+    edges.sort_unstable();
+
+    let name = |(row, col): Position| format!("\"{},{}\"", row, col);
+    let mut dot = String::from("digraph trails {\n    node [shape=circle];\n");
+    for position in nodes {
+        let height = map.height(position).unwrap_or_default();
+        let style = if height == rules.start {
+            ", shape=doublecircle, style=filled, fillcolor=palegreen"
+        } else if height == rules.end {
+            ", shape=doublecircle, style=filled, fillcolor=salmon"
+        } else {
+            ""
+        };
+        dot.push_str(&format!(
+            "    {} [label=\"{}\"{}];\n",
+            name(position),
+            height,
+            style
+        ));
+    }
+    for (from, to) in edges {
+        dot.push_str(&format!("    {} -> {};\n", name(from), name(to)));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn calculate_total_trailhead_scores(
+    map: &TopographicMap,
+    rules: &ClimbingRules,
+    analysis: &TrailAnalysis,
+) -> usize {
+    map.trailheads(rules)
+        .map(|start| analysis.score(start))
         .sum()
 }
 
-fn calculate_total_trailhead_ratings(map: &Vec<Vec<u8>>) -> usize {
-    map.iter()
-        .enumerate()
-        .flat_map(|(row_idx, row)| {
-            row.iter().enumerate().map(move |(col_idx, &height)| {
-                if height == 0 {
-                    calculate_trailhead_rating(map, row_idx, col_idx)
-                } else {
-                    0
-                }
-            })
-        })
+fn calculate_total_trailhead_ratings(
+    map: &TopographicMap,
+    rules: &ClimbingRules,
+    analysis: &TrailAnalysis,
+) -> usize {
+    map.trailheads(rules)
+        .map(|start| analysis.rating(start))
         .sum()
 }
 
 fn main() -> io::Result<()> {
     let puzzle_input = include_str!("input.data");
     let topographic_map = parse_topographic_map(puzzle_input);
+    let args = env::args().collect::<Vec<_>>();
+    let value_of = |name: &str| {
+        let index = args.iter().position(|arg| arg == name)?;
+        args.get(index + 1).map(String::as_str)
+    };
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    // `--delta <min>:<max>`, `--start <h>`, `--end <h>` and `--diagonal` change the climbing
+    // rules, e.g. `--delta -1:-1 --start 9 --end 0` walks every trail downhill
+    let default = ClimbingRules::default();
+    let deltas = match value_of("--delta") {
+        Some(range) => {
+            let (min, max) = range
+                .split_once(':')
+                .and_then(|(min, max)| Some((min.parse().ok()?, max.parse().ok()?)))
+                .ok_or_else(|| invalid("Expected `--delta <min>:<max>`"))?;
+            min..=max
+        }
+        None => default.deltas,
+    };
+    let height = |name: &str, default: u8| match value_of(name) {
+        Some(height) => height
+            .parse::<u8>()
+            .ok()
+            .filter(|&height| height <= 9)
+            .ok_or_else(|| invalid("Heights must be between 0 and 9")),
+        None => Ok(default),
+    };
+    let rules = ClimbingRules::new(
+        deltas,
+        height("--start", default.start)?,
+        height("--end", default.end)?,
+        args.iter().any(|arg| arg == "--diagonal"),
+    )?;
 
     let timer = Instant::now();
-    let score = calculate_total_trailhead_scores(&topographic_map);
+    let analysis = TrailAnalysis::new(&topographic_map, &rules);
+    let score = calculate_total_trailhead_scores(&topographic_map, &rules, &analysis);
     println!("Total score of all trailheads: {}", score);
+    let rating = calculate_total_trailhead_ratings(&topographic_map, &rules, &analysis);
+    println!("Total rating of all trailheads: {}", rating);
     println!("Time elapsed: {:?}", timer.elapsed());
 
     let timer = Instant::now();
-    let rating = calculate_total_trailhead_ratings(&topographic_map);
-    println!("Total rating of all trailheads: {}", rating);
+    let trail_count = topographic_map
+        .trailheads(&rules)
+        .map(|start| Trails::new(&topographic_map, &rules, start).count())
+        .sum::<usize>();
+    println!("Trails enumerated one by one: {}", trail_count);
     println!("Time elapsed: {:?}", timer.elapsed());
 
+    // `--dot <path>` writes the trail graph for Graphviz
+    if let Some(path) = value_of("--dot") {
+        fs::write(
+            path,
+            export_trail_graph(&topographic_map, &rules, &analysis),
+        )?;
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        calculate_total_trailhead_ratings, calculate_total_trailhead_scores, export_trail_graph,
+        parse_topographic_map, ClimbingRules, RulesError, TrailAnalysis, Trails,
+    };
+    use std::collections::HashSet;
+    use std::ops::RangeInclusive;
+
+    #[test]
+    fn test_example_scores_and_ratings() {
+        let map = parse_topographic_map(include_str!("test.data"));
+        let rules = ClimbingRules::default();
+        let analysis = TrailAnalysis::new(&map, &rules);
+        assert_eq!(
+            calculate_total_trailhead_scores(&map, &rules, &analysis),
+            36
+        );
+        assert_eq!(
+            calculate_total_trailhead_ratings(&map, &rules, &analysis),
+            81
+        );
+
+        let ratings = map
+            .trailheads(&rules)
+            .map(|start| analysis.rating(start))
+            .collect::<Vec<_>>();
+        assert_eq!(ratings, [20, 24, 10, 4, 1, 4, 5, 8, 5]);
+    }
+
+    #[test]
+    fn test_trail_enumeration() {
+        let map = parse_topographic_map(include_str!("test.data"));
+        let rules = ClimbingRules::default();
+        let analysis = TrailAnalysis::new(&map, &rules);
+
+        for start in map.trailheads(&rules) {
+            let trails = Trails::new(&map, &rules, start).collect::<HashSet<_>>();
+            assert_eq!(trails.len(), analysis.rating(start));
+
+            let summits = trails.iter().map(|trail| trail[9]).collect::<HashSet<_>>();
+            assert_eq!(summits.len(), analysis.score(start));
+
+            for trail in &trails {
+                assert_eq!(trail.len(), 10);
+                assert_eq!(trail[0], start);
+                for (height, &position) in trail.iter().enumerate() {
+                    assert_eq!(map.height(position), Some(height as u8));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_impassable_cells() {
+        let rules = ClimbingRules::default();
+        let rated = |puzzle: &str| {
+            let map = parse_topographic_map(puzzle);
+            let analysis = TrailAnalysis::new(&map, &rules);
+            map.trailheads(&rules)
+                .map(|start| (analysis.score(start), analysis.rating(start)))
+                .collect::<Vec<_>>()
+        };
+
+        let puzzle = "...0...\n...1...\n...2...\n6543456\n7.....7\n8.....8\n9.....9";
+        assert_eq!(rated(puzzle), [(2, 2)]);
+        let puzzle = "..90..9\n...1.98\n...2..7\n6543456\n765.987\n876....\n987....";
+        assert_eq!(rated(puzzle), [(4, 13)]);
+        let puzzle = "10..9..\n2...8..\n3...7..\n4567654\n...8..3\n...9..2\n.....01";
+        assert_eq!(rated(puzzle), [(1, 1), (2, 2)]);
+        let puzzle = ".....0.\n..4321.\n..5..2.\n..6543.\n..7..4.\n..8765.\n..9....";
+        assert_eq!(rated(puzzle), [(1, 3)]);
+        let puzzle = "012345\n123456\n234567\n345678\n4.6789\n56789.";
+        assert_eq!(rated(puzzle), [(2, 227)]);
+    }
+
+    #[test]
+    fn test_custom_rules() {
+        let map = parse_topographic_map(include_str!("test.data"));
+        let default = TrailAnalysis::new(&map, &ClimbingRules::default());
+
+        // Walking every trail downhill finds the same trails
+        let descending = ClimbingRules::new(-1..=-1, 9, 0, false).unwrap();
+        let analysis = TrailAnalysis::new(&map, &descending);
+        assert_eq!(
+            calculate_total_trailhead_ratings(&map, &descending, &analysis),
+            81
+        );
+        for (row, col) in map.trailheads(&descending) {
+            assert!(analysis.summits[row][col]
+                .iter()
+                .all(
+                    |&(start_row, start_col)| default.summits[start_row][start_col]
+                        .contains(&(row, col))
+                ));
+        }
+
+        // Looser rules only ever add trails
+        let loose = ClimbingRules::new(1..=2, 0, 9, true).unwrap();
+        let analysis = TrailAnalysis::new(&map, &loose);
+        assert!(calculate_total_trailhead_ratings(&map, &loose, &analysis) > 81);
+
+        // Trails stop at the end height even where they could keep climbing
+        let short = ClimbingRules::new(1..=1, 0, 1, false).unwrap();
+        let analysis = TrailAnalysis::new(&map, &short);
+        assert!(Trails::new(&map, &short, (0, 2)).all(|trail| trail.len() == 2));
+        assert_eq!(analysis.score((0, 2)), 2);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert_eq!(
+            ClimbingRules::new(1..=1, 0, 9, false),
+            Ok(ClimbingRules::default())
+        );
+        assert_eq!(
+            ClimbingRules::new(0..=1, 0, 9, false),
+            Err(RulesError::MixedDeltas)
+        );
+        assert_eq!(
+            ClimbingRules::new(-1..=1, 0, 9, false),
+            Err(RulesError::MixedDeltas)
+        );
+        assert_eq!(
+            ClimbingRules::new(RangeInclusive::new(2, 1), 0, 9, false),
+            Err(RulesError::EmptyDeltas)
+        );
+        assert_eq!(
+            ClimbingRules::new(1..=1, 5, 5, false),
+            Err(RulesError::WrongDirection { start: 5, end: 5 })
+        );
+        assert_eq!(
+            ClimbingRules::new(-1..=-1, 0, 9, false),
+            Err(RulesError::WrongDirection { start: 0, end: 9 })
+        );
+    }
+
+    #[test]
+    fn test_trail_graph_export() {
+        let map =
+            parse_topographic_map(".....0.\n..4321.\n..5..2.\n..6543.\n..7..4.\n..8765.\n..9....");
+        let rules = ClimbingRules::default();
+        let analysis = TrailAnalysis::new(&map, &rules);
+        let dot = export_trail_graph(&map, &rules, &analysis);
+
+        assert!(dot.starts_with("digraph trails {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains(
+            "    \"0,5\" [label=\"0\", shape=doublecircle, style=filled, fillcolor=palegreen];\n"
+        ));
+        assert!(dot.contains(
+            "    \"6,2\" [label=\"9\", shape=doublecircle, style=filled, fillcolor=salmon];\n"
+        ));
+        assert!(dot.contains("    \"0,5\" -> \"1,5\";\n"));
+        assert_eq!(dot.matches(" [label=").count(), 18);
+        assert_eq!(dot.matches(" -> ").count(), 19);
+    }
+}
@@ -1,4 +1,8 @@
-use std::collections::HashMap;
+use num::{BigUint, Zero};
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt;
+use std::time::Instant;
+use std::{env, fs, io, str::FromStr};
 
 fn parse_stones(puzzle: &str) -> HashMap<u64, usize> {
     puzzle
@@ -10,50 +14,738 @@ fn parse_stones(puzzle: &str) -> HashMap<u64, usize> {
         })
 }
 
-fn blink(stone_counts: &HashMap<u64, usize>) -> HashMap<u64, usize> {
-    let mut new_counts = HashMap::new();
+fn count_digits(stone: u64) -> u32 {
+    stone.checked_ilog10().map_or(1, |log| log + 1)
+}
+
+/// Which stones a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Any,
+    Equals(u64),
+    EvenDigits,
+    OddDigits,
+    DivisibleBy(u64),
+}
+
+impl Condition {
+    fn matches(&self, stone: u64) -> bool {
+        match *self {
+            Condition::Any => true,
+            Condition::Equals(value) => stone == value,
+            Condition::EvenDigits => count_digits(stone).is_multiple_of(2),
+            Condition::OddDigits => !count_digits(stone).is_multiple_of(2),
+            Condition::DivisibleBy(divisor) => stone.is_multiple_of(divisor),
+        }
+    }
+}
+
+/// What a stone turns into when its rule applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transform {
+    Keep,
+    Replace(u64),
+    /// Left and right halves of the digits, the right one dropping its leading zeroes
+    SplitDigits,
+    Multiply(u64),
+    Add(u64),
+}
+
+impl Transform {
+    fn apply(&self, stone: u64) -> Result<Vec<u64>, RuleError> {
+        let overflow = || RuleError::Overflow { stone };
+        let stones = match *self {
+            Transform::Keep => vec![stone],
+            Transform::Replace(value) => vec![value],
+            Transform::SplitDigits => {
+                let half = 10u64.pow(count_digits(stone) / 2);
+                vec![stone / half, stone % half]
+            }
+            Transform::Multiply(factor) => vec![stone.checked_mul(factor).ok_or_else(overflow)?],
+            Transform::Add(term) => vec![stone.checked_add(term).ok_or_else(overflow)?],
+        };
+
+        Ok(stones)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RuleError {
+    /// A line of the rule spec that is not `<condition> -> <transform>`, `line` counts from 1
+    InvalidRule { line: usize, text: String },
+    /// A transform whose result does not fit a `u64`
+    Overflow { stone: u64 },
+    /// The rules keep producing new stone values, so there is no closed set to build a matrix over
+    Unbounded { limit: usize },
+    /// More stones than a `usize` can count, the transition matrix counts past that
+    TooManyStones,
+}
 
-    for (&stone, &count) in stone_counts.iter() {
-        match stone {
-            0 => {
-                *new_counts.entry(1).or_insert(0) += count;
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleError::InvalidRule { line, text } => {
+                write!(f, "invalid rule {:?} on line {} of the spec", text, line)
             }
-            _ if stone.to_string().len() % 2 == 0 => {
-                let stone_str = stone.to_string();
-                let mid = stone_str.len() / 2;
-                let (first, second) = stone_str.split_at(mid);
-                let left: u64 = first.parse().expect("invalid left part");
-                let right: u64 = second.parse().expect("invalid right part");
-                *new_counts.entry(left).or_insert(0) += count;
-                *new_counts.entry(right).or_insert(0) += count;
+            RuleError::Unbounded { limit } => {
+                write!(f, "the rules produce more than {} distinct stones", limit)
             }
-            _ => {
-                let new_stone = stone * 2024;
-                *new_counts.entry(new_stone).or_insert(0) += count;
+            RuleError::TooManyStones => write!(f, "there are too many stones to count"),
+            RuleError::Overflow { stone } => {
+                write!(
+                    f,
+                    "stone {} grows past the largest engravable number",
+                    stone
+                )
             }
         }
     }
+}
+
+impl std::error::Error for RuleError {}
+
+impl From<RuleError> for io::Error {
+    fn from(error: RuleError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Ordered stone rules, the first one whose condition matches decides what a stone becomes;
+/// stones no rule matches stay as they are
+#[derive(Debug, Clone, Default)]
+struct RuleEngine {
+    rules: Vec<(Condition, Transform)>,
+    /// What every stone seen so far turns into, so each one is worked out only once
+    cache: HashMap<u64, Vec<u64>>,
+}
+
+impl RuleEngine {
+    /// The rules from the puzzle
+    fn standard() -> Self {
+        RuleEngine::default()
+            .rule(Condition::Equals(0), Transform::Replace(1))
+            .rule(Condition::EvenDigits, Transform::SplitDigits)
+            .rule(Condition::Any, Transform::Multiply(2024))
+    }
+
+    fn rule(mut self, condition: Condition, transform: Transform) -> Self {
+        self.rules.push((condition, transform));
+        self.cache.clear();
+        self
+    }
+
+    fn transform(&mut self, stone: u64) -> Result<&[u64], RuleError> {
+        if !self.cache.contains_key(&stone) {
+            let transform = self
+                .rules
+                .iter()
+                .find(|(condition, _)| condition.matches(stone))
+                .map_or(Transform::Keep, |&(_, transform)| transform);
+            self.cache.insert(stone, transform.apply(stone)?);
+        }
+
+        Ok(&self.cache[&stone])
+    }
+
+    fn blink(
+        &mut self,
+        stone_counts: &HashMap<u64, usize>,
+    ) -> Result<HashMap<u64, usize>, RuleError> {
+        let mut new_counts = HashMap::new();
+
+        for (&stone, &count) in stone_counts.iter() {
+            for &new_stone in self.transform(stone)? {
+                let new_count: &mut usize = new_counts.entry(new_stone).or_insert(0);
+                *new_count = new_count
+                    .checked_add(count)
+                    .ok_or(RuleError::TooManyStones)?;
+            }
+        }
 
-    new_counts
+        Ok(new_counts)
+    }
 }
 
-fn count_stones_after_blinking(stones: &HashMap<u64, usize>, blinks: usize) -> usize {
+/// One rule per line as `<condition> -> <transform>`, `#` starts a comment
+///
+/// Conditions are `*` (any stone), `<n>`, `even` or `odd` (number of digits) and `%<n>`
+/// (divisible by n); transforms are `<n>`, `split`, `*<n>`, `+<n>` and `keep`.
+impl FromStr for RuleEngine {
+    type Err = RuleError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut engine = RuleEngine::default();
+        for (index, line) in spec.lines().enumerate() {
+            let text = line.split('#').next().unwrap_or_default().trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            let invalid = || RuleError::InvalidRule {
+                line: index + 1,
+                text: text.to_string(),
+            };
+            let (condition, transform) = text.split_once("->").ok_or_else(invalid)?;
+            let condition = match condition.trim() {
+                "*" => Condition::Any,
+                "even" => Condition::EvenDigits,
+                "odd" => Condition::OddDigits,
+                condition => match condition.strip_prefix('%') {
+                    Some(divisor) => Condition::DivisibleBy(
+                        divisor
+                            .parse()
+                            .ok()
+                            .filter(|&divisor| divisor > 0)
+                            .ok_or_else(invalid)?,
+                    ),
+                    None => Condition::Equals(condition.parse().map_err(|_| invalid())?),
+                },
+            };
+            let transform = match transform.trim() {
+                "keep" => Transform::Keep,
+                "split" => Transform::SplitDigits,
+                transform => {
+                    let number = |value: &str| value.parse().map_err(|_| invalid());
+                    if let Some(factor) = transform.strip_prefix('*') {
+                        Transform::Multiply(number(factor)?)
+                    } else if let Some(term) = transform.strip_prefix('+') {
+                        Transform::Add(number(term)?)
+                    } else {
+                        Transform::Replace(number(transform)?)
+                    }
+                }
+            };
+            engine = engine.rule(condition, transform);
+        }
+
+        Ok(engine)
+    }
+}
+
+fn count_stones_after_blinking(
+    engine: &mut RuleEngine,
+    stones: &HashMap<u64, usize>,
+    blinks: usize,
+) -> Result<usize, RuleError> {
     let mut stone_counts = stones.clone();
     for _ in 0..blinks {
-        stone_counts = blink(&stone_counts);
+        stone_counts = engine.blink(&stone_counts)?;
     }
-    stone_counts.values().sum()
+    stone_counts
+        .values()
+        .try_fold(0usize, |total, &count| total.checked_add(count))
+        .ok_or(RuleError::TooManyStones)
+}
+
+/// Every stone value reachable from the initial stones, and what each one turns into
+///
+/// Row `i` lists the stones `stones[i]` becomes as indices into `stones`, each with how many
+/// of them it becomes.
+#[derive(Debug)]
+struct TransitionMatrix {
+    stones: Vec<u64>,
+    rows: Vec<Vec<(usize, u64)>>,
 }
 
-fn main() {
+impl TransitionMatrix {
+    /// Discovers the closed set of stones, giving up once it grows past `limit` values
+    fn new(
+        engine: &mut RuleEngine,
+        seeds: impl IntoIterator<Item = u64>,
+        limit: usize,
+    ) -> Result<Self, RuleError> {
+        let mut stones = Vec::new();
+        let mut index = HashMap::new();
+        let mut pending = Vec::new();
+        for seed in seeds {
+            if let Entry::Vacant(entry) = index.entry(seed) {
+                entry.insert(stones.len());
+                stones.push(seed);
+                pending.push(seed);
+            }
+        }
+
+        let mut rows = vec![Vec::new(); stones.len()];
+        while let Some(stone) = pending.pop() {
+            let mut row: Vec<(usize, u64)> = Vec::new();
+            for &next in engine.transform(stone)? {
+                let column = *index.entry(next).or_insert_with(|| {
+                    stones.push(next);
+                    rows.push(Vec::new());
+                    pending.push(next);
+                    stones.len() - 1
+                });
+                match row.iter_mut().find(|(existing, _)| *existing == column) {
+                    Some((_, multiplicity)) => *multiplicity += 1,
+                    None => row.push((column, 1)),
+                }
+            }
+            rows[index[&stone]] = row;
+
+            if stones.len() > limit {
+                return Err(RuleError::Unbounded { limit });
+            }
+        }
+
+        Ok(TransitionMatrix { stones, rows })
+    }
+
+    fn dimension(&self) -> usize {
+        self.stones.len()
+    }
+
+    /// Total number of stones modulo `modulus` after 0, 1, ..., `len - 1` blinks
+    fn totals(&self, stones: &HashMap<u64, usize>, len: usize, modulus: u64) -> Vec<u64> {
+        let mut counts = vec![0; self.dimension()];
+        for (index, stone) in self.stones.iter().enumerate() {
+            counts[index] = stones.get(stone).map_or(0, |&count| count as u64 % modulus);
+        }
+
+        let mut totals = Vec::with_capacity(len);
+        for _ in 0..len {
+            totals.push(counts.iter().sum::<u64>() % modulus);
+            let mut next = vec![0; self.dimension()];
+            for (row, &count) in self.rows.iter().zip(&counts) {
+                for &(column, multiplicity) in row {
+                    next[column] = (next[column] + count * multiplicity) % modulus;
+                }
+            }
+            counts = next;
+        }
+
+        totals
+    }
+
+    /// Number of stones after `blinks` blinks modulo a prime below 2^31
+    ///
+    /// Squaring the matrix itself is cubic in its dimension, a few thousand for the puzzle, so
+    /// this squares its minimal polynomial instead: the totals follow a linear recurrence of
+    /// order at most the dimension (Cayley-Hamilton), which Berlekamp-Massey recovers from
+    /// twice that many blinks, and `x^blinks` modulo the recurrence gives the answer from the
+    /// first terms.
+    fn count_modulo(&self, stones: &HashMap<u64, usize>, blinks: u64, modulus: u32) -> u64 {
+        assert!(is_prime(modulus), "Modulus must be a prime");
+        let modulus = modulus as u64;
+        let totals = self.totals(stones, 2 * self.dimension() + 1, modulus);
+        let recurrence = berlekamp_massey(&totals, modulus);
+        nth_term(&totals, &recurrence, blinks, modulus)
+    }
+
+    /// Exact number of stones after `blinks` blinks, stepping big integer counts one blink
+    /// at a time
+    ///
+    /// The counts gain a few bits every blink, so the work grows with the square of the blink
+    /// count, which is why `main` stops at `MAX_EXACT_BLINKS`.
+    fn count_exact(&self, stones: &HashMap<u64, usize>, blinks: u64) -> BigUint {
+        let mut counts = self
+            .stones
+            .iter()
+            .map(|stone| BigUint::from(stones.get(stone).copied().unwrap_or_default()))
+            .collect::<Vec<_>>();
+
+        let mut next = vec![BigUint::zero(); self.dimension()];
+        for _ in 0..blinks {
+            for (row, count) in self.rows.iter().zip(&counts) {
+                if count.is_zero() {
+                    continue;
+                }
+                for &(column, multiplicity) in row {
+                    match multiplicity {
+                        1 => next[column] += count,
+                        _ => next[column] += count * multiplicity,
+                    }
+                }
+            }
+            std::mem::swap(&mut counts, &mut next);
+            next.iter_mut().for_each(BigUint::set_zero);
+        }
+
+        counts.iter().sum()
+    }
+}
+
+/// Deterministic Miller-Rabin, exact for everything below 2^32
+fn is_prime(number: u32) -> bool {
+    if number < 2 {
+        return false;
+    }
+    for prime in [2, 3, 5, 7] {
+        if number.is_multiple_of(prime) {
+            return number == prime;
+        }
+    }
+
+    let number = number as u64;
+    let twos = (number - 1).trailing_zeros();
+    let odd = (number - 1) >> twos;
+    [2, 7, 61]
+        .into_iter()
+        .filter(|&base| base < number)
+        .all(|base| {
+            let mut power = pow_mod(base, odd, number);
+            if power == 1 || power == number - 1 {
+                return true;
+            }
+            for _ in 1..twos {
+                power = power * power % number;
+                if power == number - 1 {
+                    return true;
+                }
+            }
+            false
+        })
+}
+
+fn pow_mod(mut base: u64, mut exponent: u64, modulus: u64) -> u64 {
+    let mut result = 1 % modulus;
+    base %= modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Shortest `c` with `sequence[i] = c[0] * sequence[i - 1] + ... + c[l - 1] * sequence[i - l]`
+/// for every `i >= l`, modulo a prime below 2^31
+fn berlekamp_massey(sequence: &[u64], modulus: u64) -> Vec<u64> {
+    // Connection polynomials, `current` is the best so far and `previous` the one before the
+    // last change of order
+    let mut current = vec![1];
+    let mut previous = vec![1];
+    let (mut order, mut shift, mut last_discrepancy) = (0, 1, 1);
+
+    for (i, &term) in sequence.iter().enumerate() {
+        let discrepancy = current
+            .iter()
+            .enumerate()
+            .skip(1)
+            .fold(term, |sum, (j, &coefficient)| {
+                (sum + coefficient * sequence[i - j]) % modulus
+            });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let scale = discrepancy * pow_mod(last_discrepancy, modulus - 2, modulus) % modulus;
+        let snapshot = current.clone();
+        if current.len() < previous.len() + shift {
+            current.resize(previous.len() + shift, 0);
+        }
+        for (j, &coefficient) in previous.iter().enumerate() {
+            let correction = scale * coefficient % modulus;
+            current[j + shift] = (current[j + shift] + modulus - correction) % modulus;
+        }
+
+        if 2 * order <= i {
+            order = i + 1 - order;
+            previous = snapshot;
+            last_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+    }
+
+    current.resize(order + 1, 0);
+    current[1..]
+        .iter()
+        .map(|&coefficient| (modulus - coefficient) % modulus)
+        .collect()
+}
+
+/// Term `n` of a sequence following `recurrence` from its first terms, modulo a prime below
+/// 2^31, by repeated squaring of `x` modulo the recurrence's characteristic polynomial
+fn nth_term(sequence: &[u64], recurrence: &[u64], n: u64, modulus: u64) -> u64 {
+    if let Some(&term) = sequence.get(n as usize) {
+        return term;
+    }
+    let order = recurrence.len();
+    if order == 0 {
+        return 0;
+    }
+
+    // `x^k = c[0] x^(k - 1) + ... + c[l - 1] x^(k - l)`, applied from the top term down
+    let reduce = |mut polynomial: Vec<u64>| {
+        for k in (order..polynomial.len()).rev() {
+            let coefficient = polynomial[k];
+            if coefficient != 0 {
+                for (j, &factor) in recurrence.iter().enumerate() {
+                    let term = &mut polynomial[k - j - 1];
+                    *term = (*term + coefficient * factor) % modulus;
+                }
+            }
+        }
+        polynomial.truncate(order);
+        polynomial
+    };
+    let square = |polynomial: &[u64]| {
+        let mut product = vec![0u128; 2 * order - 1];
+        for (i, &lhs) in polynomial.iter().enumerate() {
+            for (j, &rhs) in polynomial.iter().enumerate() {
+                product[i + j] += (lhs * rhs) as u128;
+            }
+        }
+        reduce(
+            product
+                .into_iter()
+                .map(|term| (term % modulus as u128) as u64)
+                .collect(),
+        )
+    };
+
+    // Coefficients of `x^n` in `1, x, ..., x^(l - 1)`, built from the top bit of `n` down
+    let mut power = vec![1];
+    for bit in (0..u64::BITS - n.leading_zeros()).rev() {
+        power = square(&power);
+        if n >> bit & 1 == 1 {
+            power.insert(0, 0);
+            power = reduce(power);
+        }
+        power.resize(order, 0);
+    }
+
+    power
+        .iter()
+        .zip(sequence)
+        .fold(0, |sum, (&coefficient, &term)| {
+            (sum + coefficient * term) % modulus
+        })
+}
+
+/// Most blinks `--exact` counts, about six seconds for the puzzle input
+const MAX_EXACT_BLINKS: u64 = 20_000;
+
+fn main() -> io::Result<()> {
     let puzzle_input = include_str!("input.data");
     let stones = parse_stones(puzzle_input);
 
+    // `--rules <path>` replaces the puzzle's rules with the ones from a rule spec file
+    let args = env::args().collect::<Vec<_>>();
+    let mut engine = match args.iter().position(|arg| arg == "--rules") {
+        Some(index) => {
+            let path = args.get(index + 1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Expected `--rules <path>`")
+            })?;
+            RuleEngine::from_str(&fs::read_to_string(path)?)?
+        }
+        None => RuleEngine::standard(),
+    };
+
     for blink_count in [25, 75] {
-        let total_stones = count_stones_after_blinking(&stones, blink_count);
+        let timer = Instant::now();
+        let total_stones = count_stones_after_blinking(&mut engine, &stones, blink_count)?;
         println!(
             "There will be {} stones after you blink {} times",
             total_stones, blink_count
         );
+        println!("Time elapsed: {:?}", timer.elapsed());
+    }
+
+    // `--blinks <n>` counts the stones after any number of blinks, up to 10^18 and beyond,
+    // modulo the prime given with `--modulo <p>`. `--exact` also gives the exact count, for
+    // at most `MAX_EXACT_BLINKS` blinks
+    let value_of = |name: &str| {
+        let index = args.iter().position(|arg| arg == name)?;
+        args.get(index + 1)
+    };
+    if let Some(blinks) = value_of("--blinks") {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let blinks = blinks
+            .parse::<u64>()
+            .map_err(|_| invalid("Expected `--blinks <n>`"))?;
+        let modulus = match value_of("--modulo") {
+            Some(modulus) => modulus
+                .parse::<u32>()
+                .ok()
+                .filter(|&modulus| is_prime(modulus) && modulus < 1 << 31)
+                .ok_or_else(|| invalid("Modulus must be a prime below 2^31"))?,
+            None => 1_000_000_007,
+        };
+        let exact = args.iter().any(|arg| arg == "--exact");
+        if exact && blinks > MAX_EXACT_BLINKS {
+            return Err(invalid(&format!(
+                "`--exact` counts at most {} blinks",
+                MAX_EXACT_BLINKS
+            )));
+        }
+
+        let timer = Instant::now();
+        let matrix = TransitionMatrix::new(&mut engine, stones.keys().copied(), 100_000)?;
+        println!("{} distinct stones can ever appear", matrix.dimension());
+        let total_stones = matrix.count_modulo(&stones, blinks, modulus);
+        println!(
+            "There will be {} stones modulo {} after you blink {} times",
+            total_stones, modulus, blinks
+        );
+        if exact {
+            let total_stones = matrix.count_exact(&stones, blinks);
+            println!(
+                "There will be {} stones after you blink {} times",
+                total_stones, blinks
+            );
+        }
+        println!("Time elapsed: {:?}", timer.elapsed());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        berlekamp_massey, count_stones_after_blinking, nth_term, parse_stones, Condition,
+        RuleEngine, RuleError, Transform, TransitionMatrix,
+    };
+    use num::BigUint;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_example() {
+        let stones = parse_stones(include_str!("test.data"));
+        let mut engine = RuleEngine::standard();
+        for (blinks, expected) in [
+            (1, 3),
+            (2, 4),
+            (3, 5),
+            (4, 9),
+            (5, 13),
+            (6, 22),
+            (25, 55312),
+        ] {
+            assert_eq!(
+                count_stones_after_blinking(&mut engine, &stones, blinks),
+                Ok(expected)
+            );
+        }
+
+        let stones = parse_stones("0 1 10 99 999");
+        let counts = engine.blink(&stones).unwrap();
+        let expected = HashMap::from([(1, 2), (2024, 1), (0, 1), (9, 2), (2021976, 1)]);
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn test_digit_split() {
+        let split = |stone| Transform::SplitDigits.apply(stone).unwrap();
+        assert_eq!(split(1000), [10, 0]);
+        assert_eq!(split(253000), [253, 0]);
+        assert_eq!(split(1002), [10, 2]);
+        assert_eq!(split(10), [1, 0]);
+        assert_eq!(split(u64::MAX), [1844674407, 3709551615]);
+    }
+
+    #[test]
+    fn test_rule_spec() {
+        let spec = "# the puzzle's rules\n0 -> 1\neven -> split\n* -> *2024\n";
+        let engine = RuleEngine::from_str(spec).unwrap();
+        assert_eq!(engine.rules, RuleEngine::standard().rules);
+
+        let mut engine = RuleEngine::from_str("%3 -> +1\nodd -> split # never splits\n").unwrap();
+        assert_eq!(
+            engine.rules,
+            [
+                (Condition::DivisibleBy(3), Transform::Add(1)),
+                (Condition::OddDigits, Transform::SplitDigits),
+            ]
+        );
+        assert_eq!(engine.transform(9), Ok(&[10][..]));
+        assert_eq!(engine.transform(14), Ok(&[14][..]));
+
+        assert_eq!(
+            RuleEngine::from_str("0 -> 1\neven => split").unwrap_err(),
+            RuleError::InvalidRule {
+                line: 2,
+                text: "even => split".to_string()
+            }
+        );
+        assert!(RuleEngine::from_str("%0 -> keep").is_err());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut engine = RuleEngine::default().rule(Condition::Any, Transform::Multiply(2024));
+        let stones = parse_stones("1");
+        let error = count_stones_after_blinking(&mut engine, &stones, 10);
+        assert!(matches!(error, Err(RuleError::Overflow { .. })));
+    }
+
+    #[test]
+    fn test_too_many_stones() {
+        // Splitting a single digit keeps it and adds a zero, doubling the stones every blink
+        let mut engine = RuleEngine::default().rule(Condition::Any, Transform::SplitDigits);
+        let stones = parse_stones("1");
+        let count = count_stones_after_blinking(&mut engine, &stones, usize::BITS as usize - 1);
+        assert_eq!(count, Ok(1 << (usize::BITS - 1)));
+        let error = count_stones_after_blinking(&mut engine, &stones, usize::BITS as usize);
+        assert_eq!(error, Err(RuleError::TooManyStones));
+
+        let matrix = TransitionMatrix::new(&mut engine, [1], 10).unwrap();
+        let exact = matrix.count_exact(&stones, usize::BITS as u64);
+        assert_eq!(exact, BigUint::from(1u32) << usize::BITS);
+    }
+
+    #[test]
+    fn test_linear_recurrence() {
+        let modulus = 1_000_000_007;
+        let mut fibonacci = vec![0, 1];
+        for i in 2..20 {
+            fibonacci.push(fibonacci[i - 1] + fibonacci[i - 2]);
+        }
+        let recurrence = berlekamp_massey(&fibonacci, modulus);
+        assert_eq!(recurrence, [1, 1]);
+        assert_eq!(
+            nth_term(&fibonacci[..2], &recurrence, 90, modulus),
+            2880067194370816120 % modulus
+        );
+        assert_eq!(
+            nth_term(&fibonacci[..2], &recurrence, 1_000_000_008, modulus),
+            0
+        );
+    }
+
+    #[test]
+    fn test_transition_matrix() {
+        let stones = parse_stones(include_str!("test.data"));
+        let mut engine = RuleEngine::standard();
+        let matrix = TransitionMatrix::new(&mut engine, stones.keys().copied(), 1000).unwrap();
+        assert_eq!(matrix.dimension(), 76);
+
+        let modulus = 1_000_000_007;
+        for blinks in [0, 6, 25, 75] {
+            let stepped = count_stones_after_blinking(&mut engine, &stones, blinks).unwrap() as u64;
+            assert_eq!(
+                matrix.count_modulo(&stones, blinks as u64, modulus),
+                stepped % modulus as u64
+            );
+            assert_eq!(
+                matrix.count_exact(&stones, blinks as u64),
+                BigUint::from(stepped)
+            );
+        }
+        assert_eq!(
+            matrix.count_exact(&stones, 75),
+            BigUint::from(65601038650482u64)
+        );
+
+        // Exact counts past what fits a `u64` agree with the modular ones
+        let exact = matrix.count_exact(&stones, 500);
+        assert!(exact.bits() > 64);
+        for modulus in [998_244_353u32, 1_000_000_007] {
+            let residue = matrix.count_modulo(&stones, 500, modulus);
+            assert_eq!(&exact % modulus, BigUint::from(residue));
+        }
+    }
+
+    #[test]
+    fn test_unbounded_rules() {
+        let mut engine = RuleEngine::default().rule(Condition::Any, Transform::Add(1));
+        let error = TransitionMatrix::new(&mut engine, [0], 100).unwrap_err();
+        assert_eq!(error, RuleError::Unbounded { limit: 100 });
     }
 }
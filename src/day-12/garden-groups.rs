@@ -1,4 +1,5 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::env;
 use std::fmt::Display;
 use std::ops::Add;
 use std::str::FromStr;
@@ -74,6 +75,98 @@ impl Region {
     fn area(&self) -> usize {
         self.plots.len()
     }
+
+    fn perimeter(&self) -> usize {
+        self.plots
+            .iter()
+            .map(|plot| {
+                NEIGHBORS
+                    .iter()
+                    .filter(|dir| !self.plots.contains(&(*plot + **dir)))
+                    .count()
+            })
+            .sum()
+    }
+
+    fn sides(&self) -> usize {
+        count_corners(&self.plots)
+    }
+
+    /// Top-left and bottom-right plots of the smallest rectangle holding the region
+    fn bounding_box(&self) -> (Plot, Plot) {
+        let rows = self.plots.iter().map(|plot| plot.row);
+        let cols = self.plots.iter().map(|plot| plot.col);
+        (
+            Plot {
+                row: rows.clone().min().unwrap_or_default(),
+                col: cols.clone().min().unwrap_or_default(),
+            },
+            Plot {
+                row: rows.max().unwrap_or_default(),
+                col: cols.max().unwrap_or_default(),
+            },
+        )
+    }
+
+    /// Plots of other plants the region fences in, one set per hole
+    ///
+    /// Anything not in the region that cannot get out of the bounding box (grown by one plot)
+    /// without crossing the region is inside a hole.
+    fn holes(&self) -> Vec<HashSet<Plot>> {
+        let (min, max) = self.bounding_box();
+        let (min, max) = (
+            min + Plot { row: -1, col: -1 },
+            max + Plot { row: 1, col: 1 },
+        );
+        let inside = |plot: &Plot| {
+            (min.row..=max.row).contains(&plot.row) && (min.col..=max.col).contains(&plot.col)
+        };
+        let fill = |start: Plot, visited: &mut HashSet<Plot>| {
+            let mut component = HashSet::new();
+            let mut queue = VecDeque::from([start]);
+            while let Some(plot) = queue.pop_front() {
+                if !inside(&plot) || self.plots.contains(&plot) || !visited.insert(plot) {
+                    continue;
+                }
+                component.insert(plot);
+                queue.extend(NEIGHBORS.iter().map(|dir| plot + *dir));
+            }
+            component
+        };
+
+        // The grown box's corner is never part of the region, so it is outside
+        let mut visited = HashSet::new();
+        fill(min, &mut visited);
+
+        let mut holes = Vec::new();
+        for row in min.row..=max.row {
+            for col in min.col..=max.col {
+                let plot = Plot { row, col };
+                if !self.plots.contains(&plot) && !visited.contains(&plot) {
+                    holes.push(fill(plot, &mut visited));
+                }
+            }
+        }
+        holes
+    }
+}
+
+/// Number of corners of a set of plots, which equals its number of straight sides
+fn count_corners(plots: &HashSet<Plot>) -> usize {
+    plots
+        .iter()
+        .map(|plot| {
+            CORNERS
+                .iter()
+                .filter(|corner| {
+                    let opposite = plots.contains(&(*plot + corner[0]));
+                    let first = plots.contains(&(*plot + corner[1]));
+                    let second = plots.contains(&(*plot + corner[2]));
+                    (!first && !second) || (first && second && !opposite)
+                })
+                .count()
+        })
+        .sum()
 }
 
 impl Display for Region {
@@ -110,11 +203,15 @@ impl FromStr for Garden {
 }
 
 impl Garden {
+    /// Regions in the order of their top-left plot, so region ids are stable between runs
     fn get_regions(&self) -> Vec<Region> {
         let mut regions = Vec::new();
 
+        let mut plots = self.plots.keys().collect::<Vec<_>>();
+        plots.sort_by_key(|plot| (plot.row, plot.col));
+
         let mut visited: HashSet<Plot> = HashSet::new();
-        for plot in self.plots.keys() {
+        for plot in plots {
             if visited.contains(plot) {
                 continue;
             }
@@ -126,7 +223,7 @@ impl Garden {
     }
 
     fn get_region(&self, start: &Plot, visited: &mut HashSet<Plot>) -> Region {
-        let region_plant = self.plots[&start];
+        let region_plant = self.plots[start];
         let mut region_plots = HashSet::new();
 
         let mut stack = VecDeque::new();
@@ -156,76 +253,275 @@ impl Garden {
             .filter(|neighbor| self.plots.contains_key(neighbor))
             .collect()
     }
+}
 
-    fn perimeter(&self, region: &Region) -> usize {
-        region
-            .plots
+/// Regions of a garden and how they sit relative to each other, for pricing policies to query
+///
+/// Regions are identified by their index in `regions`.
+struct RegionGraph {
+    regions: Vec<Region>,
+    region_of: HashMap<Plot, usize>,
+    holes: Vec<Vec<HashSet<Plot>>>,
+    /// Regions sharing at least one fence segment with each region
+    adjacent: Vec<BTreeSet<usize>>,
+    /// Regions lying in a hole of each region, i.e. fenced in by it
+    enclosed: Vec<BTreeSet<usize>>,
+}
+
+impl RegionGraph {
+    fn new(garden: &Garden) -> Self {
+        let regions = garden.get_regions();
+        let region_of = regions
             .iter()
-            .map(|plot| {
-                4 - self
-                    .neighbors(plot)
-                    .iter()
-                    .filter(|neighbor| self.plots[neighbor] == self.plots[plot])
-                    .count()
-            })
-            .sum::<usize>()
-    }
+            .enumerate()
+            .flat_map(|(id, region)| region.plots.iter().map(move |&plot| (plot, id)))
+            .collect::<HashMap<_, _>>();
+
+        let mut adjacent = vec![BTreeSet::new(); regions.len()];
+        for (plot, &id) in &region_of {
+            for neighbor in garden.neighbors(plot) {
+                let other = region_of[&neighbor];
+                if other != id {
+                    adjacent[id].insert(other);
+                }
+            }
+        }
 
-    fn corners(&self, region: &Region) -> usize {
-        region
-            .plots
+        let holes = regions.iter().map(Region::holes).collect::<Vec<_>>();
+        let enclosed = holes
             .iter()
-            .map(|plot| {
-                CORNERS
+            .map(|holes| {
+                holes
                     .iter()
-                    .filter(|corner| {
-                        let opposite = self.plots.get(&(*plot + corner[0]));
-                        let first = self.plots.get(&(*plot + corner[1]));
-                        let second = self.plots.get(&(*plot + corner[2]));
-                        let plot_char = self.plots.get(plot);
-                        (plot_char != second && plot_char != first)
-                            || (plot_char == second && plot_char == first && plot_char != opposite)
-                    })
-                    .count()
+                    .flatten()
+                    .filter_map(|plot| region_of.get(plot).copied())
+                    .collect()
             })
-            .sum::<usize>()
+            .collect();
+
+        RegionGraph {
+            regions,
+            region_of,
+            holes,
+            adjacent,
+            enclosed,
+        }
     }
 
-    fn calculate_price_by_perimeter_policy(&self) -> usize {
-        self.get_regions()
-            .iter()
-            .map(|region| self.perimeter(region) * region.area())
-            .sum()
+    fn len(&self) -> usize {
+        self.regions.len()
     }
 
-    fn calculate_price_by_sides_policy(&self) -> usize {
-        self.get_regions()
-            .iter()
-            .map(|region| self.corners(&region) * region.area())
-            .sum()
+    fn region(&self, id: usize) -> &Region {
+        &self.regions[id]
+    }
+
+    fn region_at(&self, plot: &Plot) -> Option<usize> {
+        self.region_of.get(plot).copied()
+    }
+
+    fn holes(&self, id: usize) -> usize {
+        self.holes[id].len()
     }
+
+    /// Sides of the region's outline, ignoring its holes
+    fn outer_sides(&self, id: usize) -> usize {
+        let mut filled = self.regions[id].plots.clone();
+        filled.extend(self.holes[id].iter().flatten());
+        count_corners(&filled)
+    }
+
+    /// Sides around the region's holes
+    fn inner_sides(&self, id: usize) -> usize {
+        self.regions[id].sides() - self.outer_sides(id)
+    }
+
+    fn adjacent(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.adjacent[id].iter().copied()
+    }
+
+    /// Regions fenced in by the given one, including those nested deeper inside them
+    fn enclosed(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.enclosed[id].iter().copied()
+    }
+
+    /// Regions fencing in the given one, from the outermost to the innermost
+    fn enclosers(&self, id: usize) -> Vec<usize> {
+        let mut enclosers = (0..self.len())
+            .filter(|&other| self.enclosed[other].contains(&id))
+            .collect::<Vec<_>>();
+        enclosers.sort_by_key(|&other| std::cmp::Reverse(self.enclosed[other].len()));
+        enclosers
+    }
+
+    fn is_enclosed(&self, id: usize) -> bool {
+        self.enclosed.iter().any(|enclosed| enclosed.contains(&id))
+    }
+
+    /// Sum of what the policy charges for each region
+    fn price(&self, policy: impl Fn(&RegionGraph, usize) -> usize) -> usize {
+        (0..self.len()).map(|id| policy(self, id)).sum()
+    }
+}
+
+fn describe_region(graph: &RegionGraph, id: usize) -> String {
+    let region = graph.region(id);
+    let (min, max) = region.bounding_box();
+    let mut description = format!(
+        "#{} '{}': area {}, perimeter {}, sides {} ({} outer, {} inner), bounds {}..{}, \
+         holes {}, adjacent to {:?}",
+        id,
+        region.plant,
+        region.area(),
+        region.perimeter(),
+        region.sides(),
+        graph.outer_sides(id),
+        graph.inner_sides(id),
+        min,
+        max,
+        graph.holes(id),
+        graph.adjacent(id).collect::<Vec<_>>(),
+    );
+    if graph.holes(id) > 0 {
+        let enclosed = graph.enclosed(id).collect::<Vec<_>>();
+        description.push_str(&format!(", encloses {:?}", enclosed));
+    }
+    if graph.is_enclosed(id) {
+        description.push_str(&format!(", enclosed by {:?}", graph.enclosers(id)));
+    }
+    description
+}
+
+fn calculate_price_by_perimeter_policy(graph: &RegionGraph) -> usize {
+    graph.price(|graph, id| graph.region(id).perimeter() * graph.region(id).area())
+}
+
+fn calculate_price_by_sides_policy(graph: &RegionGraph) -> usize {
+    graph.price(|graph, id| graph.region(id).sides() * graph.region(id).area())
 }
 
 fn main() {
     let puzzle_input = include_str!("input.data");
     let garden = Garden::from_str(puzzle_input).expect("Failed to parse garden map");
 
-    // let regions = garden.get_regions();
-    // for region in &regions {
-    //     println!("{region:}");
-    // }
+    let timer = std::time::Instant::now();
+    let graph = RegionGraph::new(&garden);
+    println!("Found {} regions", graph.len());
+    println!("Time elapsed: {:?}", timer.elapsed());
+
+    // `--regions` lists every region with its place in the region graph
+    let args = env::args().collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--regions") {
+        for id in 0..graph.len() {
+            println!("{}", describe_region(&graph, id));
+        }
+    }
+
+    // `--query <row>,<col>` describes the region growing on that plot
+    if let Some(index) = args.iter().position(|arg| arg == "--query") {
+        let plot = args
+            .get(index + 1)
+            .and_then(|plot| plot.split_once(','))
+            .and_then(|(row, col)| Some(Plot::new(row.parse().ok()?, col.parse().ok()?)));
+        match plot.and_then(|plot| graph.region_at(&plot)) {
+            Some(id) => println!("{}", describe_region(&graph, id)),
+            None => println!("Expected `--query <row>,<col>` for a plot of the garden"),
+        }
+    }
 
     let timer = std::time::Instant::now();
     println!(
         "Total garden price (by perimeter) is {}",
-        garden.calculate_price_by_perimeter_policy()
+        calculate_price_by_perimeter_policy(&graph)
     );
     println!("Time elapsed: {:?}", timer.elapsed());
 
     let timer = std::time::Instant::now();
     println!(
         "Total garden price (by sides) is {}",
-        garden.calculate_price_by_sides_policy()
+        calculate_price_by_sides_policy(&graph)
     );
     println!("Time elapsed: {:?}", timer.elapsed());
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        calculate_price_by_perimeter_policy, calculate_price_by_sides_policy, Garden, Plot,
+        RegionGraph,
+    };
+    use std::str::FromStr;
+
+    fn region_graph(garden_map: &str) -> RegionGraph {
+        RegionGraph::new(&Garden::from_str(garden_map).unwrap())
+    }
+
+    #[test]
+    fn test_examples() {
+        for (garden_map, perimeter_price, sides_price) in [
+            ("AAAA\nBBCD\nBBCC\nEEEC", 140, 80),
+            ("OOOOO\nOXOXO\nOOOOO\nOXOXO\nOOOOO", 772, 436),
+            ("EEEEE\nEXXXX\nEEEEE\nEXXXX\nEEEEE", 692, 236),
+            ("AAAAAA\nAAABBA\nAAABBA\nABBAAA\nABBAAA\nAAAAAA", 1184, 368),
+            (include_str!("test.data"), 1930, 1206),
+        ] {
+            let graph = region_graph(garden_map);
+            assert_eq!(calculate_price_by_perimeter_policy(&graph), perimeter_price);
+            assert_eq!(calculate_price_by_sides_policy(&graph), sides_price);
+        }
+    }
+
+    #[test]
+    fn test_holes_and_enclosure() {
+        let graph = region_graph("AAAAAA\nAAABBA\nAAABBA\nABBAAA\nABBAAA\nAAAAAA");
+        assert_eq!(graph.len(), 3);
+        let outer = graph.region_at(&Plot::new(0, 0)).unwrap();
+        let inner = graph.region_at(&Plot::new(1, 3)).unwrap();
+
+        assert_eq!(graph.holes(outer), 2);
+        assert_eq!(graph.outer_sides(outer), 4);
+        assert_eq!(graph.inner_sides(outer), 8);
+        assert_eq!(graph.enclosed(outer).count(), 2);
+        assert!(!graph.is_enclosed(outer));
+
+        assert_eq!(graph.holes(inner), 0);
+        assert_eq!(graph.outer_sides(inner), 4);
+        assert_eq!(graph.inner_sides(inner), 0);
+        assert_eq!(graph.enclosers(inner), [outer]);
+        assert_eq!(graph.adjacent(inner).collect::<Vec<_>>(), [outer]);
+        assert_eq!(
+            graph.region(inner).bounding_box(),
+            (Plot::new(1, 3), Plot::new(2, 4))
+        );
+
+        // The E region touches the border on three sides, so it fences nothing in
+        let graph = region_graph("EEEEE\nEXXXX\nEEEEE\nEXXXX\nEEEEE");
+        assert!((0..graph.len()).all(|id| graph.holes(id) == 0 && !graph.is_enclosed(id)));
+    }
+
+    #[test]
+    fn test_nested_regions() {
+        let graph = region_graph("AAAAA\nABBBA\nABCBA\nABBBA\nAAAAA");
+        let (a, b, c) = (0, 1, 2);
+        assert_eq!(graph.region_at(&Plot::new(2, 2)), Some(c));
+        assert_eq!(graph.enclosers(c), [a, b]);
+        assert_eq!(graph.enclosers(b), [a]);
+        assert_eq!(graph.enclosed(a).collect::<Vec<_>>(), [b, c]);
+        assert_eq!(graph.adjacent(b).collect::<Vec<_>>(), [a, c]);
+        assert_eq!(graph.adjacent(c).collect::<Vec<_>>(), [b]);
+        assert_eq!((graph.holes(a), graph.inner_sides(a)), (1, 4));
+        assert_eq!(graph.region_at(&Plot::new(5, 0)), None);
+
+        // New policies only need the graph, e.g. outer fences at full price and inner ones
+        // at half price, with enclosed regions getting theirs for free
+        let price = graph.price(|graph, id| {
+            let area = graph.region(id).area();
+            if graph.is_enclosed(id) {
+                0
+            } else {
+                area * (2 * graph.outer_sides(id) + graph.inner_sides(id)) / 2
+            }
+        });
+        assert_eq!(price, 16 * 6);
+    }
+}
@@ -1,59 +1,566 @@
-use regex::Regex;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::path::Path;
-use std::{fs, io};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Mul,
+    Do,
+    Dont,
+}
+
+impl Opcode {
+    // Longer keywords go first so that `don't` is never lexed as `do`
+    const ALL: [Opcode; 3] = [Opcode::Dont, Opcode::Mul, Opcode::Do];
+
+    fn keyword(self) -> &'static [u8] {
+        match self {
+            Opcode::Mul => b"mul",
+            Opcode::Do => b"do",
+            Opcode::Dont => b"don't",
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Opcode::Mul => 2,
+            Opcode::Do | Opcode::Dont => 0,
+        }
+    }
+
+    fn max_statement_len(self) -> usize {
+        let arity = self.arity();
+        let separators = arity.saturating_sub(1);
+        self.keyword().len() + 2 + arity * Parser::MAX_OPERAND_DIGITS + separators
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Instruction {
     Mul(i32, i32),
+    Do,
+    Dont,
 }
 
-fn load_program_source_code(file_path: &Path) -> io::Result<String> {
-    let result = fs::read_to_string(file_path);
-    match result {
-        Ok(text) => Ok(text.trim().to_string()),
-        Err(e) => Err(e),
+impl Instruction {
+    fn new(opcode: Opcode, operands: &[i32]) -> Self {
+        match opcode {
+            Opcode::Mul => Instruction::Mul(operands[0], operands[1]),
+            Opcode::Do => Instruction::Do,
+            Opcode::Dont => Instruction::Dont,
+        }
     }
 }
 
-fn compile(source_code: &str) -> Vec<Instruction> {
-    let regex = Regex::new(r"mul\((\d{1,3}),(\d{1,3})\)").unwrap();
+impl Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Mul(lhs, rhs) => write!(f, "mul({},{})", lhs, rhs),
+            Instruction::Do => write!(f, "do()"),
+            Instruction::Dont => write!(f, "don't()"),
+        }
+    }
+}
 
-    let mut operations = Vec::new();
-    for cap in regex.captures_iter(source_code) {
-        if let (Ok(lhs), Ok(rhs)) = (cap[1].parse::<i32>(), cap[2].parse::<i32>()) {
-            operations.push(Instruction::Mul(lhs, rhs));
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Statement {
+    offset: usize,
+    instruction: Instruction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    Keyword(Opcode),
+    Number,
+    OpenParen,
+    CloseParen,
+    Comma,
+    Unknown,
+}
+
+impl TokenKind {
+    fn describe(self) -> &'static str {
+        match self {
+            TokenKind::Keyword(_) => "instruction",
+            TokenKind::Number => "number",
+            TokenKind::OpenParen => "'('",
+            TokenKind::CloseParen => "')'",
+            TokenKind::Comma => "','",
+            TokenKind::Unknown => "unknown byte",
         }
     }
-    operations
 }
 
-fn compile_with_reenabling_feature(source_code: &str) -> Vec<Instruction> {
-    let regex = Regex::new(r"(mul)\((\d{1,3}),(\d{1,3})\)|(don't)\(\)|(do)\(\)").unwrap();
+#[derive(Debug, Clone, Copy)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
 
-    let mut operations = Vec::new();
-    let mut skip = false;
-    for cap in regex.captures_iter(source_code) {
-        if let Some(_) = cap.get(1) {
-            if !skip {
-                let lhs = cap[2].parse::<i32>().unwrap();
-                let rhs = cap[3].parse::<i32>().unwrap();
-                operations.push(Instruction::Mul(lhs, rhs));
-            }
-        } else if let Some(_) = cap.get(4) {
-            skip = true;
-        } else if let Some(_) = cap.get(5) {
-            skip = false;
+struct Lexer<'a> {
+    source: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a [u8]) -> Self {
+        Self {
+            source,
+            position: 0,
         }
     }
-    operations
+
+    fn seek(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    fn text(&self, token: &Token) -> &'a [u8] {
+        &self.source[token.start..token.end]
+    }
 }
 
-fn execute(operations: Vec<Instruction>) -> i32 {
-    operations
-        .iter()
-        .map(|instruction| match instruction {
-            Instruction::Mul(lhs, rhs) => lhs * rhs,
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.position;
+        let rest = self.source.get(start..).filter(|rest| !rest.is_empty())?;
+
+        let (kind, length) = if let Some(opcode) = Opcode::ALL
+            .into_iter()
+            .find(|opcode| rest.starts_with(opcode.keyword()))
+        {
+            (TokenKind::Keyword(opcode), opcode.keyword().len())
+        } else {
+            match rest[0] {
+                b'(' => (TokenKind::OpenParen, 1),
+                b')' => (TokenKind::CloseParen, 1),
+                b',' => (TokenKind::Comma, 1),
+                b'0'..=b'9' => {
+                    let digits = rest.iter().take_while(|byte| byte.is_ascii_digit()).count();
+                    (TokenKind::Number, digits)
+                }
+                _ => (TokenKind::Unknown, 1),
+            }
+        };
+
+        self.position = start + length;
+        Some(Token {
+            kind,
+            start,
+            end: self.position,
         })
-        .sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RejectReason {
+    Unexpected {
+        expected: TokenKind,
+        found: Option<u8>,
+    },
+    OperandTooLong {
+        digits: usize,
+    },
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::Unexpected {
+                expected,
+                found: Some(byte),
+            } => write!(
+                f,
+                "expected {} but found {:?}",
+                expected.describe(),
+                *byte as char
+            ),
+            RejectReason::Unexpected {
+                expected,
+                found: None,
+            } => write!(f, "expected {} but found end of input", expected.describe()),
+            RejectReason::OperandTooLong { digits } => write!(
+                f,
+                "operand has {} digits, at most {} allowed",
+                digits,
+                Parser::MAX_OPERAND_DIGITS
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rejection {
+    offset: usize,
+    opcode: Opcode,
+    text: String,
+    reason: RejectReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseEvent {
+    Accepted(Statement),
+    Rejected(Rejection),
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    limit: usize,
+}
+
+impl<'a> Parser<'a> {
+    const MAX_OPERAND_DIGITS: usize = 3;
+
+    fn new(source: &'a [u8]) -> Self {
+        Self::with_limit(source, source.len())
+    }
+
+    // Statements are only recognised if they start before `limit`,
+    // the bytes past it are still available as lookahead
+    fn with_limit(source: &'a [u8], limit: usize) -> Self {
+        Self {
+            lexer: Lexer::new(source),
+            limit,
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.lexer.position
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token, RejectReason> {
+        match self.lexer.next() {
+            Some(token) if token.kind == kind => Ok(token),
+            Some(token) => Err(RejectReason::Unexpected {
+                expected: kind,
+                found: Some(self.lexer.text(&token)[0]),
+            }),
+            None => Err(RejectReason::Unexpected {
+                expected: kind,
+                found: None,
+            }),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<i32, RejectReason> {
+        let token = self.expect(TokenKind::Number)?;
+        let digits = self.lexer.text(&token);
+        if digits.len() > Self::MAX_OPERAND_DIGITS {
+            return Err(RejectReason::OperandTooLong {
+                digits: digits.len(),
+            });
+        }
+
+        Ok(digits
+            .iter()
+            .fold(0, |value, digit| value * 10 + (digit - b'0') as i32))
+    }
+
+    fn parse_call(&mut self, opcode: Opcode) -> Result<Instruction, RejectReason> {
+        self.expect(TokenKind::OpenParen)?;
+
+        let mut operands = Vec::with_capacity(opcode.arity());
+        for index in 0..opcode.arity() {
+            if index > 0 {
+                self.expect(TokenKind::Comma)?;
+            }
+            operands.push(self.parse_operand()?);
+        }
+
+        self.expect(TokenKind::CloseParen)?;
+        Ok(Instruction::new(opcode, &operands))
+    }
+
+    fn next_event(&mut self) -> Option<ParseEvent> {
+        loop {
+            if self.lexer.position >= self.limit {
+                return None;
+            }
+
+            let token = self.lexer.next()?;
+            let TokenKind::Keyword(opcode) = token.kind else {
+                continue;
+            };
+
+            match self.parse_call(opcode) {
+                Ok(instruction) => {
+                    return Some(ParseEvent::Accepted(Statement {
+                        offset: token.start,
+                        instruction,
+                    }))
+                }
+                Err(reason) => {
+                    let text = &self.lexer.source[token.start..self.lexer.position];
+                    // A broken call may still hide a valid one right after its first byte
+                    self.lexer.seek(token.start + 1);
+                    return Some(ParseEvent::Rejected(Rejection {
+                        offset: token.start,
+                        opcode,
+                        text: String::from_utf8_lossy(text).into_owned(),
+                        reason,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Parser<'_> {
+    type Item = Statement;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let ParseEvent::Accepted(statement) = self.next_event()? {
+                return Some(statement);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    IgnoreConditionals,
+    HonorConditionals,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ExecutionError {
+    Overflow { offset: usize },
+}
+
+impl Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::Overflow { offset } => {
+                write!(f, "result overflow at byte offset {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+impl From<ExecutionError> for io::Error {
+    fn from(error: ExecutionError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+#[derive(Debug)]
+struct Interpreter {
+    mode: Mode,
+    enabled: bool,
+    result: i64,
+}
+
+impl Interpreter {
+    fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            enabled: true,
+            result: 0,
+        }
+    }
+
+    // Returns how much the statement added to the result
+    fn step(&mut self, statement: &Statement) -> Result<i64, ExecutionError> {
+        let overflow = ExecutionError::Overflow {
+            offset: statement.offset,
+        };
+
+        let contribution = match statement.instruction {
+            Instruction::Mul(lhs, rhs) if self.enabled => {
+                i64::from(lhs).checked_mul(i64::from(rhs)).ok_or(overflow)?
+            }
+            Instruction::Mul(..) => 0,
+            Instruction::Do => {
+                self.enabled = true;
+                0
+            }
+            Instruction::Dont => {
+                self.enabled = self.mode == Mode::IgnoreConditionals;
+                0
+            }
+        };
+
+        self.result = self.result.checked_add(contribution).ok_or(overflow)?;
+        Ok(contribution)
+    }
+
+    fn run(mut self, program: &[Statement]) -> Result<i64, ExecutionError> {
+        for statement in program {
+            self.step(statement)?;
+        }
+        Ok(self.result)
+    }
+}
+
+struct StreamScanner<R> {
+    reader: R,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    base_offset: usize,
+    pending: VecDeque<Statement>,
+    finished: bool,
+}
+
+impl<R: Read> StreamScanner<R> {
+    const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+    fn new(reader: R) -> Self {
+        Self::with_chunk_size(reader, Self::DEFAULT_CHUNK_SIZE)
+    }
+
+    fn with_chunk_size(reader: R, chunk_size: usize) -> Self {
+        Self {
+            reader,
+            chunk_size: chunk_size.max(1),
+            buffer: Vec::new(),
+            base_offset: 0,
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    fn lookahead() -> usize {
+        Opcode::ALL
+            .into_iter()
+            .map(Opcode::max_statement_len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn scan_next_chunk(&mut self) -> io::Result<()> {
+        let read = (&mut self.reader)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut self.buffer)?;
+        let eof = read < self.chunk_size;
+
+        // Anything that starts in the tail might straddle the chunk boundary,
+        // so it is left in the buffer until more data arrives
+        let limit = if eof {
+            self.buffer.len()
+        } else {
+            self.buffer.len().saturating_sub(Self::lookahead())
+        };
+
+        let mut parser = Parser::with_limit(&self.buffer, limit);
+        for statement in parser.by_ref() {
+            self.pending.push_back(Statement {
+                offset: self.base_offset + statement.offset,
+                ..statement
+            });
+        }
+
+        let consumed = parser.position().min(self.buffer.len());
+        self.buffer.drain(..consumed);
+        self.base_offset += consumed;
+        self.finished = eof;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for StreamScanner<R> {
+    type Item = io::Result<Statement>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(statement) = self.pending.pop_front() {
+                return Some(Ok(statement));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(e) = self.scan_next_chunk() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TraceEntry {
+    Executed {
+        statement: Statement,
+        enabled: bool,
+        contribution: i64,
+    },
+    Rejected(Rejection),
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEntry::Executed {
+                statement,
+                enabled,
+                contribution,
+            } => write!(
+                f,
+                "{:>10}  {:<16}{:<10}+{}",
+                statement.offset,
+                statement.instruction.to_string(),
+                if *enabled { "enabled" } else { "disabled" },
+                contribution
+            ),
+            TraceEntry::Rejected(rejection) => write!(
+                f,
+                "{:>10}  {:<16}rejected: {}",
+                rejection.offset,
+                format!("{:?}", rejection.text),
+                rejection.reason
+            ),
+        }
+    }
+}
+
+fn load_program_source_code(file_path: &Path) -> io::Result<String> {
+    fs::read_to_string(file_path)
+}
+
+fn compile(source_code: &str) -> Vec<Statement> {
+    Parser::new(source_code.as_bytes()).collect()
+}
+
+fn execute(program: &[Statement], mode: Mode) -> Result<i64, ExecutionError> {
+    Interpreter::new(mode).run(program)
+}
+
+fn trace(source_code: &str, mode: Mode) -> Result<Vec<TraceEntry>, ExecutionError> {
+    let mut parser = Parser::new(source_code.as_bytes());
+    let mut interpreter = Interpreter::new(mode);
+
+    let mut entries = Vec::new();
+    while let Some(event) = parser.next_event() {
+        let entry = match event {
+            ParseEvent::Accepted(statement) => {
+                let enabled = interpreter.enabled;
+                let contribution = interpreter.step(&statement)?;
+                TraceEntry::Executed {
+                    statement,
+                    enabled,
+                    contribution,
+                }
+            }
+            ParseEvent::Rejected(rejection) => TraceEntry::Rejected(rejection),
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn execute_stream<R: Read>(scanner: StreamScanner<R>, mode: Mode) -> io::Result<i64> {
+    let mut interpreter = Interpreter::new(mode);
+    for statement in scanner {
+        interpreter.step(&statement?)?;
+    }
+    Ok(interpreter.result)
 }
 
 fn main() -> io::Result<()> {
@@ -61,13 +568,189 @@ fn main() -> io::Result<()> {
     let source_code = load_program_source_code(file_path)?;
     println!("Program: {:?}", source_code);
 
+    if env::args().any(|arg| arg == "--trace") {
+        for entry in trace(&source_code, Mode::HonorConditionals)? {
+            println!("{}", entry);
+        }
+    }
+
     let program = compile(&source_code);
-    let result = execute(program);
+    let result = execute(&program, Mode::IgnoreConditionals)?;
     println!("Program result: {}", result);
 
-    let program = compile_with_reenabling_feature(&source_code);
-    let result = execute(program);
+    let result = execute(&program, Mode::HonorConditionals)?;
     println!("Program result with re-enabling feature: {}", result);
 
+    let scanner = StreamScanner::new(File::open(file_path)?);
+    let result = execute_stream(scanner, Mode::IgnoreConditionals)?;
+    println!("Streamed program result: {}", result);
+
+    let scanner = StreamScanner::new(File::open(file_path)?);
+    let result = execute_stream(scanner, Mode::HonorConditionals)?;
+    println!(
+        "Streamed program result with re-enabling feature: {}",
+        result
+    );
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile, execute, execute_stream, trace, ExecutionError, Instruction, Interpreter, Mode,
+        Opcode, RejectReason, Rejection, Statement, StreamScanner, TokenKind, TraceEntry,
+    };
+
+    const EXAMPLE: &str =
+        "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
+
+    #[test]
+    fn test_compile_example() {
+        let program = compile(EXAMPLE);
+        assert_eq!(
+            program,
+            vec![
+                Statement {
+                    offset: 1,
+                    instruction: Instruction::Mul(2, 4)
+                },
+                Statement {
+                    offset: 20,
+                    instruction: Instruction::Dont
+                },
+                Statement {
+                    offset: 28,
+                    instruction: Instruction::Mul(5, 5)
+                },
+                Statement {
+                    offset: 48,
+                    instruction: Instruction::Mul(11, 8)
+                },
+                Statement {
+                    offset: 59,
+                    instruction: Instruction::Do
+                },
+                Statement {
+                    offset: 64,
+                    instruction: Instruction::Mul(8, 5)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_example() {
+        let program = compile(EXAMPLE);
+        assert_eq!(execute(&program, Mode::IgnoreConditionals).unwrap(), 161);
+        assert_eq!(execute(&program, Mode::HonorConditionals).unwrap(), 48);
+    }
+
+    #[test]
+    fn test_stream_matches_in_memory_path() {
+        let source = format!("{}{}", EXAMPLE, include_str!("input.data"));
+        let program = compile(&source);
+
+        for chunk_size in [1, 2, 3, 5, 7, 11, 13, 64, 4096] {
+            let scanner = StreamScanner::with_chunk_size(source.as_bytes(), chunk_size);
+            let streamed = scanner.collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(streamed, program, "chunk size {}", chunk_size);
+
+            for mode in [Mode::IgnoreConditionals, Mode::HonorConditionals] {
+                let scanner = StreamScanner::with_chunk_size(source.as_bytes(), chunk_size);
+                assert_eq!(
+                    execute_stream(scanner, mode).unwrap(),
+                    execute(&program, mode).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_overflow_is_reported() {
+        let mut interpreter = Interpreter::new(Mode::IgnoreConditionals);
+        interpreter.result = i64::MAX;
+        let statement = Statement {
+            offset: 42,
+            instruction: Instruction::Mul(2, 3),
+        };
+        assert!(matches!(
+            interpreter.step(&statement),
+            Err(ExecutionError::Overflow { offset: 42 })
+        ));
+    }
+
+    #[test]
+    fn test_near_misses_are_ignored() {
+        let program = compile("mul(4*mul(6,9!?(12,34)mul ( 2 , 4 )mul(1234,5)mumul(3,3)");
+        assert_eq!(
+            program,
+            vec![Statement {
+                offset: 48,
+                instruction: Instruction::Mul(3, 3)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_trace_example() {
+        let entries = trace(EXAMPLE, Mode::HonorConditionals).unwrap();
+        let executed = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::Executed {
+                    statement,
+                    enabled,
+                    contribution,
+                } => Some((statement.instruction, *enabled, *contribution)),
+                TraceEntry::Rejected(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            executed,
+            vec![
+                (Instruction::Mul(2, 4), true, 8),
+                (Instruction::Dont, true, 0),
+                (Instruction::Mul(5, 5), false, 0),
+                (Instruction::Mul(11, 8), false, 0),
+                (Instruction::Do, false, 0),
+                (Instruction::Mul(8, 5), true, 40),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trace_rejections() {
+        let entries = trace("mul(4*mul ( 2 , 4 )mul(1234,5)", Mode::HonorConditionals).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                TraceEntry::Rejected(Rejection {
+                    offset: 0,
+                    opcode: Opcode::Mul,
+                    text: "mul(4*".to_string(),
+                    reason: RejectReason::Unexpected {
+                        expected: TokenKind::Comma,
+                        found: Some(b'*'),
+                    },
+                }),
+                TraceEntry::Rejected(Rejection {
+                    offset: 6,
+                    opcode: Opcode::Mul,
+                    text: "mul ".to_string(),
+                    reason: RejectReason::Unexpected {
+                        expected: TokenKind::OpenParen,
+                        found: Some(b' '),
+                    },
+                }),
+                TraceEntry::Rejected(Rejection {
+                    offset: 19,
+                    opcode: Opcode::Mul,
+                    text: "mul(1234".to_string(),
+                    reason: RejectReason::OperandTooLong { digits: 4 },
+                }),
+            ]
+        );
+    }
+}
@@ -1,99 +1,343 @@
+use std::collections::HashMap;
+use std::env;
 use std::fs::File;
 use std::io;
 use std::io::BufRead;
 use std::path::Path;
+use std::str::FromStr;
 
-fn load_word_search(file_path: &Path) -> io::Result<Vec<String>> {
-    let file = File::open(file_path)?;
-    let lines = io::BufReader::new(file).lines();
-    lines.collect::<Result<Vec<_>, _>>() // Collect and propagate errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Right,
+    Down,
+    DownRight,
+    DownLeft,
+    Left,
+    Up,
+    UpLeft,
+    UpRight,
 }
 
-fn check_word(
-    word_search: &Vec<String>,
-    word: &str,
-    row: isize,
-    col: isize,
-    dir: (isize, isize),
-) -> bool {
-    let rows = word_search.len() as isize;
-    let cols = word_search[0].len() as isize;
-    let word_len = word.len();
+impl Direction {
+    const ALL: [Direction; 8] = [
+        Direction::Right,
+        Direction::Down,
+        Direction::DownRight,
+        Direction::DownLeft,
+        Direction::Left,
+        Direction::Up,
+        Direction::UpLeft,
+        Direction::UpRight,
+    ];
 
-    for i in 0..word_len {
-        let new_row = row + i as isize * dir.0;
-        let new_col = col + i as isize * dir.1;
-        if new_row < 0 || new_row >= rows || new_col < 0 || new_col >= cols {
-            return false;
+    fn offset(self) -> (isize, isize) {
+        match self {
+            Direction::Right => (0, 1),
+            Direction::Down => (1, 0),
+            Direction::DownRight => (1, 1),
+            Direction::DownLeft => (1, -1),
+            Direction::Left => (0, -1),
+            Direction::Up => (-1, 0),
+            Direction::UpLeft => (-1, -1),
+            Direction::UpRight => (-1, 1),
         }
+    }
+}
 
-        let lhs = word_search[new_row as usize].chars().nth(new_col as usize);
-        let rhs = Some(word.chars().nth(i).unwrap());
-        if lhs != rhs {
-            return false;
-        }
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<u8, usize>,
+    word: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct WordMatch<'a> {
+    row: usize,
+    col: usize,
+    direction: Direction,
+    word: &'a str,
+}
+
+impl WordMatch<'_> {
+    fn cells(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let (dr, dc) = self.direction.offset();
+        (0..self.word.len() as isize).map(move |i| {
+            (
+                (self.row as isize + i * dr) as usize,
+                (self.col as isize + i * dc) as usize,
+            )
+        })
     }
-    true
 }
 
-fn count_word(word_search: &Vec<String>, word: &str) -> usize {
-    let mut count = 0;
+#[derive(Debug)]
+struct WordFinder {
+    words: Vec<String>,
+    nodes: Vec<TrieNode>,
+}
 
-    let directions = vec![
-        (0, 1),   // Right
-        (1, 0),   // Down
-        (1, 1),   // Diagonal down-right
-        (1, -1),  // Diagonal down-left
-        (0, -1),  // Left (reverse horizontal)
-        (-1, 0),  // Up (reverse vertical)
-        (-1, -1), // Diagonal up-left
-        (-1, 1),  // Diagonal up-right
-    ];
+impl WordFinder {
+    fn new<S: AsRef<str>>(words: &[S]) -> Self {
+        let mut finder = WordFinder {
+            words: Vec::new(),
+            nodes: vec![TrieNode::default()],
+        };
+        for word in words {
+            finder.insert(word.as_ref());
+        }
+        finder
+    }
+
+    fn insert(&mut self, word: &str) {
+        if word.is_empty() {
+            return;
+        }
+
+        let mut node = 0;
+        for &letter in word.as_bytes() {
+            node = match self.nodes[node].children.get(&letter) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(letter, child);
+                    child
+                }
+            };
+        }
+
+        if self.nodes[node].word.is_none() {
+            self.nodes[node].word = Some(self.words.len());
+            self.words.push(word.to_string());
+        }
+    }
+
+    fn find_all(&self, word_search: &[String]) -> Vec<WordMatch<'_>> {
+        let grid = word_search
+            .iter()
+            .map(|line| line.as_bytes())
+            .collect::<Vec<_>>();
+        let rows = grid.len() as isize;
+
+        let mut matches = Vec::new();
+        for (row, line) in grid.iter().enumerate() {
+            for col in 0..line.len() {
+                for direction in Direction::ALL {
+                    let (dr, dc) = direction.offset();
+                    let (mut r, mut c) = (row as isize, col as isize);
+
+                    // Walk the trie and the grid together until the prefix stops matching
+                    let mut node = 0;
+                    while r >= 0 && r < rows && c >= 0 && (c as usize) < grid[r as usize].len() {
+                        let letter = grid[r as usize][c as usize];
+                        let Some(&child) = self.nodes[node].children.get(&letter) else {
+                            break;
+                        };
+
+                        node = child;
+                        if let Some(word) = self.nodes[node].word {
+                            matches.push(WordMatch {
+                                row,
+                                col,
+                                direction,
+                                word: &self.words[word],
+                            });
+                        }
 
-    // Iterate over every cell in the grid
-    let rows = word_search.len();
-    let cols = word_search[0].len();
-    for row in 0..rows {
-        for col in 0..cols {
-            for &dir in &directions {
-                if check_word(&word_search, word, row as isize, col as isize, dir) {
-                    count += 1;
+                        r += dr;
+                        c += dc;
+                    }
                 }
             }
         }
+
+        matches
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Pattern {
+    // `None` is a wildcard that matches any letter
+    cells: Vec<Vec<Option<u8>>>,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let rows = template
+            .lines()
+            .map(|line| line.trim_end())
+            .collect::<Vec<_>>();
+        let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+        if width == 0 {
+            return Err("pattern template is empty".to_string());
+        }
+        if !template.is_ascii() {
+            return Err("pattern template must be ASCII".to_string());
+        }
+
+        let cells = rows
+            .iter()
+            .map(|row| {
+                let mut cells = row
+                    .bytes()
+                    .map(|letter| (letter != b'.').then_some(letter))
+                    .collect::<Vec<_>>();
+                cells.resize(width, None);
+                cells
+            })
+            .collect();
+
+        Ok(Pattern { cells })
+    }
+}
+
+impl Pattern {
+    fn height(&self) -> usize {
+        self.cells.len()
     }
 
-    count
+    fn width(&self) -> usize {
+        self.cells[0].len()
+    }
+
+    fn rotate(&self) -> Self {
+        let cells = (0..self.width())
+            .map(|col| {
+                (0..self.height())
+                    .rev()
+                    .map(|row| self.cells[row][col])
+                    .collect()
+            })
+            .collect();
+        Pattern { cells }
+    }
+
+    fn reflect(&self) -> Self {
+        let cells = self
+            .cells
+            .iter()
+            .map(|row| row.iter().rev().copied().collect())
+            .collect();
+        Pattern { cells }
+    }
+
+    /// The whole stencil, wildcards included, has to lie inside the grid; otherwise wildcards
+    /// could hang over the right and bottom edges but never the left and top ones, and the
+    /// rotations of one template would match differently near the edges
+    fn matches_at(&self, grid: &[&[u8]], row: usize, col: usize) -> bool {
+        self.cells.iter().enumerate().all(|(dr, cells)| {
+            cells.iter().enumerate().all(|(dc, cell)| {
+                grid.get(row + dr)
+                    .and_then(|line| line.get(col + dc))
+                    .is_some_and(|found| cell.is_none_or(|letter| letter == *found))
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symmetry {
+    Exact,
+    Rotations,
+    RotationsAndReflections,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PatternMatch {
+    row: usize,
+    col: usize,
+    variant: usize,
+}
+
+#[derive(Debug)]
+struct PatternMatcher {
+    variants: Vec<Pattern>,
 }
 
-fn count_xmas_patterns(word_search: &Vec<String>) -> usize {
-    let mut count = 0;
+impl PatternMatcher {
+    fn new(pattern: &Pattern, symmetry: Symmetry) -> Self {
+        let mut candidates = vec![pattern.clone()];
+        if symmetry != Symmetry::Exact {
+            for _ in 0..3 {
+                let next = candidates.last().unwrap().rotate();
+                candidates.push(next);
+            }
+        }
+        if symmetry == Symmetry::RotationsAndReflections {
+            let reflected = candidates.iter().map(Pattern::reflect).collect::<Vec<_>>();
+            candidates.extend(reflected);
+        }
 
-    let rows = word_search.len();
-    let cols = word_search[0].len();
-    for row in 1..rows - 1 {
-        for col in 1..cols - 1 {
-            if word_search[row].chars().nth(col).unwrap() == 'A' {
-                let prev_row = row as isize - 1;
-                let prev_col = col as isize - 1;
-                let next_col = col as isize + 1;
+        // Symmetric templates produce the same variant more than once
+        let mut variants: Vec<Pattern> = Vec::new();
+        for candidate in candidates {
+            if !variants.contains(&candidate) {
+                variants.push(candidate);
+            }
+        }
 
-                let top_left_to_bottom_right =
-                    check_word(word_search, "MAS", prev_row, prev_col, (1, 1))
-                        || check_word(word_search, "SAM", prev_row, prev_col, (1, 1));
+        PatternMatcher { variants }
+    }
 
-                let top_right_to_bottom_left =
-                    check_word(word_search, "MAS", prev_row, next_col, (1, -1))
-                        || check_word(word_search, "SAM", prev_row, next_col, (1, -1));
+    fn find_all(&self, word_search: &[String]) -> Vec<PatternMatch> {
+        let grid = word_search
+            .iter()
+            .map(|line| line.as_bytes())
+            .collect::<Vec<_>>();
 
-                if top_left_to_bottom_right && top_right_to_bottom_left {
-                    count += 1;
+        let mut matches = Vec::new();
+        for (row, line) in grid.iter().enumerate() {
+            for col in 0..line.len() {
+                for (variant, pattern) in self.variants.iter().enumerate() {
+                    if pattern.matches_at(&grid, row, col) {
+                        matches.push(PatternMatch { row, col, variant });
+                    }
                 }
             }
         }
+
+        matches
+    }
+}
+
+/// Columns are bytes, as everywhere in the word search, so every byte outside a match
+/// becomes a `.`, including each byte of a multi-byte character
+fn render_matches(word_search: &[String], matches: &[WordMatch]) -> String {
+    let mut canvas = word_search
+        .iter()
+        .map(|line| vec![b'.'; line.len()])
+        .collect::<Vec<_>>();
+
+    for word_match in matches {
+        for (row, col) in word_match.cells() {
+            canvas[row][col] = word_search[row].as_bytes()[col];
+        }
     }
 
-    count
+    canvas
+        .iter()
+        .map(|line| String::from_utf8_lossy(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn load_word_search(file_path: &Path) -> io::Result<Vec<String>> {
+    let file = File::open(file_path)?;
+    let lines = io::BufReader::new(file).lines();
+    lines.collect::<Result<Vec<_>, _>>() // Collect and propagate errors
+}
+
+fn count_word(word_search: &[String], word: &str) -> usize {
+    WordFinder::new(&[word]).find_all(word_search).len()
+}
+
+fn count_xmas_patterns(word_search: &[String]) -> usize {
+    let pattern = "M.S\n.A.\nM.S".parse::<Pattern>().unwrap();
+    PatternMatcher::new(&pattern, Symmetry::Rotations)
+        .find_all(word_search)
+        .len()
 }
 
 fn main() -> io::Result<()> {
@@ -108,5 +352,216 @@ fn main() -> io::Result<()> {
     let xmas_pattern_count = count_xmas_patterns(&word_search);
     println!("The 'x-max' appears {} times", xmas_pattern_count);
 
+    // `--pattern M.S/.A./M.S [--rotate] [--reflect]` locates a letter stencil, `.` is a wildcard
+    let args = env::args().collect::<Vec<_>>();
+    if let Some(index) = args.iter().position(|arg| arg == "--pattern") {
+        let template = args.get(index + 1).map(|arg| arg.replace('/', "\n"));
+        let pattern = template
+            .unwrap_or_default()
+            .parse::<Pattern>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let symmetry = match (
+            args.iter().any(|arg| arg == "--rotate"),
+            args.iter().any(|arg| arg == "--reflect"),
+        ) {
+            (_, true) => Symmetry::RotationsAndReflections,
+            (true, false) => Symmetry::Rotations,
+            (false, false) => Symmetry::Exact,
+        };
+
+        let matches = PatternMatcher::new(&pattern, symmetry).find_all(&word_search);
+        println!("The pattern appears {} times", matches.len());
+        for pattern_match in &matches {
+            println!(
+                "Variant {} at ({}, {})",
+                pattern_match.variant, pattern_match.row, pattern_match.col
+            );
+        }
+    }
+
+    // `--mask [WORD...]` prints the grid with everything but the found words masked out, the
+    // words run up to the next `--` flag
+    let mut args = env::args().skip_while(|arg| arg != "--mask");
+    if args.next().is_some() {
+        let mut words = args
+            .take_while(|arg| !arg.starts_with("--"))
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            words.push(word.to_string());
+        }
+
+        let finder = WordFinder::new(&words);
+        let matches = finder.find_all(&word_search);
+        println!("{}", render_matches(&word_search, &matches));
+        for word_match in &matches {
+            println!(
+                "{} at ({}, {}) going {:?}",
+                word_match.word, word_match.row, word_match.col, word_match.direction
+            );
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        count_word, count_xmas_patterns, render_matches, Direction, Pattern, PatternMatch,
+        PatternMatcher, Symmetry, WordFinder, WordMatch,
+    };
+
+    fn example() -> Vec<String> {
+        include_str!("test.data")
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_count_word_example() {
+        assert_eq!(count_word(&example(), "XMAS"), 18);
+    }
+
+    #[test]
+    fn test_find_all_reports_positions() {
+        let word_search = vec!["XMAS".to_string(), "ABCD".to_string()];
+        let finder = WordFinder::new(&["XMAS", "SAM", "DA"]);
+        let mut matches = finder.find_all(&word_search);
+        matches.sort_by_key(|word_match| (word_match.row, word_match.col, word_match.word));
+
+        assert_eq!(
+            matches,
+            vec![
+                WordMatch {
+                    row: 0,
+                    col: 0,
+                    direction: Direction::Right,
+                    word: "XMAS",
+                },
+                WordMatch {
+                    row: 0,
+                    col: 3,
+                    direction: Direction::Left,
+                    word: "SAM",
+                },
+                WordMatch {
+                    row: 1,
+                    col: 3,
+                    direction: Direction::UpLeft,
+                    word: "DA",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_matches_example() {
+        let word_search = example();
+        let finder = WordFinder::new(&["XMAS"]);
+        let rendered = render_matches(&word_search, &finder.find_all(&word_search));
+        assert_eq!(
+            rendered,
+            [
+                "....XXMAS.",
+                ".SAMXMS...",
+                "...S..A...",
+                "..A.A.MS.X",
+                "XMASAMX.MM",
+                "X.....XA.A",
+                "S.S.S.S.SS",
+                ".A.A.A.A.A",
+                "..M.M.M.MM",
+                ".X.X.XMASX",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_count_xmas_patterns_example() {
+        assert_eq!(count_xmas_patterns(&example()), 9);
+    }
+
+    #[test]
+    fn test_pattern_variants() {
+        let pattern = "M.S\n.A.\nM.S".parse::<Pattern>().unwrap();
+        assert_eq!(
+            PatternMatcher::new(&pattern, Symmetry::Exact)
+                .variants
+                .len(),
+            1
+        );
+        assert_eq!(
+            PatternMatcher::new(&pattern, Symmetry::Rotations)
+                .variants
+                .len(),
+            4
+        );
+        assert_eq!(
+            PatternMatcher::new(&pattern, Symmetry::RotationsAndReflections)
+                .variants
+                .len(),
+            4
+        );
+
+        let plus = ".M.\nMAS\n.S.".parse::<Pattern>().unwrap();
+        assert_eq!(
+            PatternMatcher::new(&plus, Symmetry::RotationsAndReflections)
+                .variants
+                .len(),
+            4
+        );
+
+        let corner = "XM\n.A".parse::<Pattern>().unwrap();
+        assert_eq!(
+            PatternMatcher::new(&corner, Symmetry::RotationsAndReflections)
+                .variants
+                .len(),
+            8
+        );
+    }
+
+    #[test]
+    fn test_pattern_locations() {
+        let word_search = vec!["XMX".to_string(), "MAS".to_string(), "XSX".to_string()];
+        let plus = ".M.\nMAS\n.S.".parse::<Pattern>().unwrap();
+        let matches = PatternMatcher::new(&plus, Symmetry::Exact).find_all(&word_search);
+        assert_eq!(
+            matches,
+            vec![PatternMatch {
+                row: 0,
+                col: 0,
+                variant: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pattern_near_edges() {
+        // Every rotation of a stencil wider than the grid is out of bounds, wildcards or not
+        let pattern = "A.".parse::<Pattern>().unwrap();
+        let matcher = PatternMatcher::new(&pattern, Symmetry::Rotations);
+        assert_eq!(matcher.variants.len(), 4);
+        assert!(matcher.find_all(&["A".to_string()]).is_empty());
+
+        // On a grid that fits them, each rotation matches once
+        let word_search = vec!["BBB".to_string(), "BAB".to_string(), "BBB".to_string()];
+        let matches = matcher.find_all(&word_search);
+        let mut variants = matches
+            .iter()
+            .map(|found| found.variant)
+            .collect::<Vec<_>>();
+        variants.sort_unstable();
+        assert_eq!(variants, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_render_matches_non_ascii() {
+        let word_search = vec!["éXMAS".to_string(), "XMAS".to_string()];
+        let finder = WordFinder::new(&["XMAS"]);
+        let matches = finder.find_all(&word_search);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(render_matches(&word_search, &matches), "..XMAS\nXMAS");
+    }
+}
@@ -1,15 +1,73 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fmt::{self, Display};
 use std::fs::File;
 use std::io;
 use std::io::BufRead;
 use std::path::Path;
 
-fn load_printer_instructions(file_path: &Path) -> io::Result<(Vec<(i32, i32)>, Vec<Vec<i32>>)> {
+type Rule = (i32, i32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum OrderingError {
+    // Pages in rule order, the last page must precede the first one again
+    Cycle(Vec<i32>),
+    // A page printed more than once in the same update
+    DuplicatePage(i32),
+}
+
+impl Display for OrderingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderingError::Cycle(pages) => {
+                let path = pages
+                    .iter()
+                    .chain(pages.first())
+                    .map(|page| page.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                write!(f, "ordering rules form a cycle: {}", path)
+            }
+            OrderingError::DuplicatePage(page) => {
+                write!(f, "page {} appears more than once in an update", page)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OrderingError {}
+
+impl From<OrderingError> for io::Error {
+    fn from(error: OrderingError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+fn find_contradictory_rules(rules: &[Rule]) -> Vec<Rule> {
+    let known: HashSet<Rule> = rules.iter().copied().collect();
+    let mut contradictions: Vec<Rule> = known
+        .iter()
+        .filter(|&&(lhs, rhs)| lhs < rhs && known.contains(&(rhs, lhs)))
+        .copied()
+        .collect();
+    contradictions.sort();
+    contradictions
+}
+
+fn check_duplicate_pages(update: &[i32]) -> Result<(), OrderingError> {
+    let mut seen = HashSet::new();
+    match update.iter().find(|&&page| !seen.insert(page)) {
+        Some(&page) => Err(OrderingError::DuplicatePage(page)),
+        None => Ok(()),
+    }
+}
+
+fn load_printer_instructions(file_path: &Path) -> io::Result<(Vec<Rule>, Vec<Vec<i32>>)> {
     let file = File::open(file_path)?;
-    let mut lines = io::BufReader::new(file).lines().filter_map(Result::ok);
+    let mut lines = io::BufReader::new(file).lines().map_while(Result::ok);
 
     // Collect the rules section until the first empty line
-    let rules: Vec<(i32, i32)> = lines
+    let rules: Vec<Rule> = lines
         .by_ref()
         .take_while(|line| !line.trim().is_empty())
         .map(|line| {
@@ -30,45 +88,109 @@ fn load_printer_instructions(file_path: &Path) -> io::Result<(Vec<(i32, i32)>, V
         })
         .collect();
 
+    let contradictions = find_contradictory_rules(&rules);
+    if !contradictions.is_empty() {
+        let pairs = contradictions
+            .iter()
+            .map(|(lhs, rhs)| format!("{lhs}|{rhs} and {rhs}|{lhs}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("contradictory ordering rules: {}", pairs),
+        ));
+    }
+    for update in &updates {
+        check_duplicate_pages(update)?;
+    }
+
     Ok((rules, updates))
 }
 
-fn is_valid_update(update: &Vec<i32>, rules: &Vec<(i32, i32)>) -> bool {
-    let page_set: HashSet<i32> = update.iter().cloned().collect();
+#[derive(Debug, Default, Clone)]
+struct RuleIndex {
+    // page -> pages that must be printed after it
+    successors: HashMap<i32, HashSet<i32>>,
+}
 
-    for &(lhs, rhs) in rules {
-        if page_set.contains(&lhs) && page_set.contains(&rhs) {
-            let lhs_position = update.iter().position(|&page| page == lhs).unwrap();
-            let rhs_position = update.iter().position(|&page| page == rhs).unwrap();
-            if lhs_position >= rhs_position {
-                return false;
-            }
+impl RuleIndex {
+    fn new(rules: &[Rule]) -> Self {
+        let mut index = RuleIndex::default();
+        for &rule in rules {
+            index.insert(rule);
         }
+        index
+    }
+
+    fn insert(&mut self, (lhs, rhs): Rule) -> bool {
+        self.successors.entry(lhs).or_default().insert(rhs)
+    }
+
+    fn remove(&mut self, (lhs, rhs): Rule) -> bool {
+        let Some(successors) = self.successors.get_mut(&lhs) else {
+            return false;
+        };
+
+        let removed = successors.remove(&rhs);
+        if successors.is_empty() {
+            self.successors.remove(&lhs);
+        }
+        removed
+    }
+
+    fn must_precede(&self, lhs: i32, rhs: i32) -> bool {
+        self.successors
+            .get(&lhs)
+            .is_some_and(|successors| successors.contains(&rhs))
+    }
+
+    fn successors(&self, page: i32) -> impl Iterator<Item = i32> + '_ {
+        self.successors.get(&page).into_iter().flatten().copied()
+    }
+
+    fn violations(&self, update: &[i32]) -> Vec<Rule> {
+        let positions: HashMap<i32, usize> = update
+            .iter()
+            .enumerate()
+            .map(|(position, &page)| (page, position))
+            .collect();
+
+        let mut violated = Vec::new();
+        for (position, &page) in update.iter().enumerate() {
+            let mut broken = self
+                .successors(page)
+                .filter(|successor| positions.get(successor).is_some_and(|&p| p <= position))
+                .map(|successor| (page, successor))
+                .collect::<Vec<_>>();
+            broken.sort_by_key(|(_, successor)| positions[successor]);
+            violated.extend(broken);
+        }
+        violated
     }
-    true
 }
 
-fn sum_middle_pages(updates: &Vec<Vec<i32>>, rules: &Vec<(i32, i32)>) -> i32 {
+fn is_valid_update(update: &[i32], index: &RuleIndex) -> bool {
+    index.violations(update).is_empty()
+}
+
+fn sum_middle_pages(updates: &[Vec<i32>], index: &RuleIndex) -> i32 {
     updates
         .iter()
-        .filter(|update| is_valid_update(update, &rules))
+        .filter(|update| is_valid_update(update, index))
         .map(|update| update[update.len() / 2])
         .sum()
 }
 
 #[allow(dead_code)]
-fn correct_update_with_brute_force(update: &Vec<i32>, rules: &Vec<(i32, i32)>) -> Vec<i32> {
-    let mut corrected_update = update.clone();
+fn correct_update_with_brute_force(update: &[i32], index: &RuleIndex) -> Vec<i32> {
+    let mut corrected_update = update.to_vec();
     let mut changed = true;
 
     while changed {
         changed = false;
-        for &(lhs, rhs) in rules {
-            let lhs_position = corrected_update.iter().position(|&page| page == lhs);
-            let rhs_position = corrected_update.iter().position(|&page| page == rhs);
-
-            if let (Some(lhs_idx), Some(rhs_idx)) = (lhs_position, rhs_position) {
-                if lhs_idx > rhs_idx {
+        for lhs_idx in 0..corrected_update.len() {
+            for rhs_idx in 0..lhs_idx {
+                if index.must_precede(corrected_update[lhs_idx], corrected_update[rhs_idx]) {
                     corrected_update.swap(lhs_idx, rhs_idx);
                     changed = true;
                 }
@@ -79,7 +201,40 @@ fn correct_update_with_brute_force(update: &Vec<i32>, rules: &Vec<(i32, i32)>) -
     corrected_update
 }
 
-fn correct_update_with_topological_sort(update: &Vec<i32>, rules: &Vec<(i32, i32)>) -> Vec<i32> {
+fn find_cycle(remaining: &HashSet<i32>, graph: &HashMap<i32, Vec<i32>>) -> Vec<i32> {
+    // Every page left over by Kahn's algorithm still has a predecessor among
+    // the leftovers, so walking predecessors must eventually revisit a page.
+    // Pages go in ascending order and each keeps its lowest predecessor, so the
+    // reported cycle does not depend on hash order.
+    let mut pages = remaining.iter().copied().collect::<Vec<_>>();
+    pages.sort_unstable();
+    let mut predecessors: HashMap<i32, i32> = HashMap::new();
+    for &page in &pages {
+        for neighbor in graph[&page].iter().filter(|page| remaining.contains(page)) {
+            predecessors.entry(*neighbor).or_insert(page);
+        }
+    }
+
+    let mut walk = vec![pages[0]];
+    loop {
+        let previous = predecessors[walk.last().unwrap()];
+        if let Some(start) = walk.iter().position(|&page| page == previous) {
+            let mut cycle = walk.split_off(start);
+            cycle.reverse();
+            let lowest = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap();
+            cycle.rotate_left(lowest);
+            return cycle;
+        }
+        walk.push(previous);
+    }
+}
+
+fn correct_update_with_topological_sort(
+    update: &[i32],
+    index: &RuleIndex,
+) -> Result<Vec<i32>, OrderingError> {
+    check_duplicate_pages(update)?;
+
     let mut in_degree = HashMap::new();
     let mut graph = HashMap::new();
 
@@ -90,18 +245,20 @@ fn correct_update_with_topological_sort(update: &Vec<i32>, rules: &Vec<(i32, i32
     }
 
     // Build graph and in-degree based on rules
-    for &(lhs, rhs) in rules {
-        if update.contains(&lhs) && update.contains(&rhs) {
-            graph.get_mut(&lhs).unwrap().push(rhs);
-            *in_degree.get_mut(&rhs).unwrap() += 1;
+    for &lhs in update {
+        for rhs in index.successors(lhs) {
+            if let Some(degree) = in_degree.get_mut(&rhs) {
+                graph.get_mut(&lhs).unwrap().push(rhs);
+                *degree += 1;
+            }
         }
     }
 
-    // Perform topological sorting
-    let mut queue: VecDeque<i32> = in_degree
+    // Perform topological sorting, seeding the queue in update order keeps the result deterministic
+    let mut queue: VecDeque<i32> = update
         .iter()
-        .filter(|&(_, &degree)| degree == 0)
-        .map(|(&page, _)| page)
+        .filter(|page| in_degree[page] == 0)
+        .copied()
         .collect();
 
     let mut sorted = Vec::new();
@@ -116,16 +273,167 @@ fn correct_update_with_topological_sort(update: &Vec<i32>, rules: &Vec<(i32, i32
         }
     }
 
-    sorted
+    let remaining: HashSet<i32> = update
+        .iter()
+        .filter(|page| !sorted.contains(page))
+        .copied()
+        .collect();
+    if !remaining.is_empty() {
+        return Err(OrderingError::Cycle(find_cycle(&remaining, &graph)));
+    }
+
+    Ok(sorted)
+}
+
+fn has_unique_order(update: &[i32], index: &RuleIndex) -> Result<bool, OrderingError> {
+    // A valid order is the only one when every neighbouring pair is pinned by a rule,
+    // otherwise those two pages could be swapped
+    let sorted = correct_update_with_topological_sort(update, index)?;
+    Ok(sorted
+        .windows(2)
+        .all(|pair| index.must_precede(pair[0], pair[1])))
+}
+
+const MAX_COUNTED_PAGES: usize = 20;
+
+fn count_orderings(update: &[i32], index: &RuleIndex) -> Option<u64> {
+    let pages = update.len();
+    if pages > MAX_COUNTED_PAGES {
+        return None;
+    }
+
+    let required: Vec<usize> = update
+        .iter()
+        .map(|&page| {
+            update
+                .iter()
+                .enumerate()
+                .filter(|&(_, &other)| index.must_precede(other, page))
+                .fold(0, |mask, (position, _)| mask | (1 << position))
+        })
+        .collect();
+
+    // ways[placed] is the number of valid ways to print the pages of `placed` first
+    let mut ways = vec![0u64; 1 << pages];
+    ways[0] = 1;
+    for placed in 0..ways.len() {
+        if ways[placed] == 0 {
+            continue;
+        }
+        for (position, &mask) in required.iter().enumerate() {
+            let bit = 1 << position;
+            if placed & bit == 0 && placed & mask == mask {
+                ways[placed | bit] += ways[placed];
+            }
+        }
+    }
+
+    ways.last().copied()
+}
+
+struct Orderings {
+    pages: Vec<i32>,
+    successors: Vec<Vec<usize>>,
+    in_degree: Vec<usize>,
+    placed: Vec<bool>,
+    prefix: Vec<usize>,
+    // Next candidate to try at each depth of the search
+    cursor: Vec<usize>,
+    exhausted: bool,
+}
+
+impl Orderings {
+    fn new(update: &[i32], index: &RuleIndex) -> Self {
+        let pages = update.to_vec();
+        let successors: Vec<Vec<usize>> = pages
+            .iter()
+            .map(|&page| {
+                (0..pages.len())
+                    .filter(|&other| index.must_precede(page, pages[other]))
+                    .collect()
+            })
+            .collect();
+
+        let mut in_degree = vec![0; pages.len()];
+        for &successor in successors.iter().flatten() {
+            in_degree[successor] += 1;
+        }
+
+        // With a cycle the search would explore every dead end before giving up
+        let exhausted = correct_update_with_topological_sort(update, index).is_err();
+
+        Orderings {
+            placed: vec![false; pages.len()],
+            prefix: Vec::with_capacity(pages.len()),
+            cursor: vec![0; pages.len() + 1],
+            pages,
+            successors,
+            in_degree,
+            exhausted,
+        }
+    }
+
+    fn place(&mut self, position: usize) {
+        self.placed[position] = true;
+        self.prefix.push(position);
+        for &successor in &self.successors[position] {
+            self.in_degree[successor] -= 1;
+        }
+    }
+
+    fn step_back(&mut self) {
+        match self.prefix.pop() {
+            Some(position) => {
+                self.placed[position] = false;
+                for &successor in &self.successors[position] {
+                    self.in_degree[successor] += 1;
+                }
+            }
+            None => self.exhausted = true,
+        }
+    }
+}
+
+impl Iterator for Orderings {
+    type Item = Vec<i32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.exhausted {
+            let depth = self.prefix.len();
+            if depth == self.pages.len() {
+                let ordering = self.prefix.iter().map(|&i| self.pages[i]).collect();
+                self.step_back();
+                return Some(ordering);
+            }
+
+            let candidate = (self.cursor[depth]..self.pages.len())
+                .find(|&i| !self.placed[i] && self.in_degree[i] == 0);
+            match candidate {
+                Some(position) => {
+                    self.cursor[depth] = position + 1;
+                    self.cursor[depth + 1] = 0;
+                    self.place(position);
+                }
+                None => {
+                    self.cursor[depth] = 0;
+                    self.step_back();
+                }
+            }
+        }
+        None
+    }
 }
 
-fn sum_middle_pages_with_corrections(updates: &Vec<Vec<i32>>, rules: &Vec<(i32, i32)>) -> i32 {
+fn sum_middle_pages_with_corrections(
+    updates: &[Vec<i32>],
+    index: &RuleIndex,
+) -> Result<i32, OrderingError> {
     updates
         .iter()
-        .filter(|update| !is_valid_update(update, rules))
+        .filter(|update| !is_valid_update(update, index))
         .map(|update| {
-            let corrected = correct_update_with_topological_sort(update, rules);
-            corrected[corrected.len() / 2]
+            let corrected = correct_update_with_topological_sort(update, index)?;
+            Ok(corrected[corrected.len() / 2])
         })
         .sum()
 }
@@ -136,11 +444,163 @@ fn main() -> io::Result<()> {
     // println!("{:?}", rules);
     // println!("{:?}", updates);
 
-    let middle_pages_sum = sum_middle_pages(&updates, &rules);
+    let mut index = RuleIndex::new(&rules);
+
+    // `--drop-rule <a>|<b>` (repeatable) leaves a rule out, to see which updates rely on it
+    let args = env::args().collect::<Vec<_>>();
+    for (position, arg) in args.iter().enumerate() {
+        if arg != "--drop-rule" {
+            continue;
+        }
+        let rule = args
+            .get(position + 1)
+            .and_then(|rule| rule.split_once('|'))
+            .and_then(|(lhs, rhs)| Some((lhs.trim().parse().ok()?, rhs.trim().parse().ok()?)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Expected `--drop-rule <a>|<b>`",
+                )
+            })?;
+        if !index.remove(rule) {
+            println!("There is no rule {}|{} to drop", rule.0, rule.1);
+        }
+    }
+
+    let middle_pages_sum = sum_middle_pages(&updates, &index);
     println!("The sum of middle pages numbers is {}", middle_pages_sum);
 
-    let middle_pages_sum = sum_middle_pages_with_corrections(&updates, &rules);
+    let middle_pages_sum = sum_middle_pages_with_corrections(&updates, &index)?;
     println!("The sum of middle pages numbers is {}", middle_pages_sum);
 
+    let mut ambiguous_updates = 0;
+    for update in &updates {
+        if has_unique_order(update, &index)? {
+            continue;
+        }
+
+        ambiguous_updates += 1;
+        let count = count_orderings(update, &index)
+            .map_or_else(|| "too many".to_string(), |count| count.to_string());
+        let examples = Orderings::new(update, &index).take(2).collect::<Vec<_>>();
+        println!(
+            "Update {:?} has {} valid orders, for example {:?}",
+            update, count, examples
+        );
+    }
+    println!(
+        "The number of updates with more than one valid order is {}",
+        ambiguous_updates
+    );
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        correct_update_with_topological_sort, count_orderings, find_contradictory_rules,
+        has_unique_order, load_printer_instructions, sum_middle_pages,
+        sum_middle_pages_with_corrections, OrderingError, Orderings, RuleIndex,
+    };
+    use std::path::Path;
+
+    #[test]
+    fn test_example() {
+        let file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/day-5/test.data");
+        let (rules, updates) = load_printer_instructions(&file_path).unwrap();
+        let index = RuleIndex::new(&rules);
+        assert_eq!(sum_middle_pages(&updates, &index), 143);
+        assert_eq!(sum_middle_pages_with_corrections(&updates, &index), Ok(123));
+    }
+
+    #[test]
+    fn test_cycle_is_reported() {
+        let index = RuleIndex::new(&[(1, 2), (2, 3), (3, 1), (4, 1)]);
+        let error = correct_update_with_topological_sort(&[4, 3, 2, 1], &index).unwrap_err();
+        assert_eq!(error, OrderingError::Cycle(vec![1, 2, 3]));
+        assert_eq!(
+            error.to_string(),
+            "ordering rules form a cycle: 1 -> 2 -> 3 -> 1"
+        );
+    }
+
+    #[test]
+    fn test_cycle_report_is_deterministic() {
+        // Two disjoint cycles, and pages with more than one predecessor in the first
+        let index = RuleIndex::new(&[(1, 2), (2, 3), (3, 1), (3, 2), (2, 1), (10, 20), (20, 10)]);
+        for _ in 0..50 {
+            let error = correct_update_with_topological_sort(&[20, 3, 10, 2, 1], &index);
+            assert_eq!(error, Err(OrderingError::Cycle(vec![1, 2])));
+        }
+    }
+
+    #[test]
+    fn test_duplicate_pages() {
+        let index = RuleIndex::new(&[(5, 6)]);
+        let error = correct_update_with_topological_sort(&[6, 6, 5], &index);
+        assert_eq!(error, Err(OrderingError::DuplicatePage(6)));
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "page 6 appears more than once in an update"
+        );
+    }
+
+    #[test]
+    fn test_contradictory_rules() {
+        let rules = vec![(1, 2), (3, 4), (2, 1), (5, 6), (4, 3)];
+        assert_eq!(find_contradictory_rules(&rules), vec![(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn test_rule_index_violations() {
+        let mut index = RuleIndex::new(&[(97, 75), (75, 47), (47, 61), (29, 13)]);
+        assert!(index.must_precede(97, 75));
+        assert!(!index.must_precede(75, 97));
+
+        assert_eq!(index.violations(&[75, 97, 47, 61]), vec![(97, 75)]);
+        assert_eq!(index.violations(&[61, 13, 29]), vec![(29, 13)]);
+
+        assert!(index.remove((29, 13)));
+        assert!(!index.remove((29, 13)));
+        assert!(index.violations(&[61, 13, 29]).is_empty());
+
+        assert!(index.insert((61, 47)));
+        assert_eq!(index.violations(&[47, 61]), vec![(61, 47)]);
+    }
+
+    #[test]
+    fn test_ambiguous_orderings() {
+        let index = RuleIndex::new(&[(1, 2), (1, 3), (2, 4), (3, 4)]);
+        let update = [4, 3, 2, 1];
+
+        assert_eq!(has_unique_order(&update, &index), Ok(false));
+        assert_eq!(count_orderings(&update, &index), Some(2));
+        assert_eq!(
+            Orderings::new(&update, &index).collect::<Vec<_>>(),
+            vec![vec![1, 3, 2, 4], vec![1, 2, 3, 4]]
+        );
+
+        let index = RuleIndex::new(&[(1, 2), (2, 3), (1, 3)]);
+        assert_eq!(has_unique_order(&[3, 1, 2], &index), Ok(true));
+        assert_eq!(count_orderings(&[3, 1, 2], &index), Some(1));
+    }
+
+    #[test]
+    fn test_orderings_without_rules() {
+        let index = RuleIndex::default();
+        assert_eq!(count_orderings(&[1, 2, 3, 4], &index), Some(24));
+        assert_eq!(Orderings::new(&[1, 2, 3, 4], &index).count(), 24);
+        assert_eq!(
+            Orderings::new(&[], &index).collect::<Vec<_>>(),
+            vec![vec![]]
+        );
+    }
+
+    #[test]
+    fn test_orderings_with_cycle() {
+        let index = RuleIndex::new(&[(1, 2), (2, 3), (3, 1)]);
+        assert_eq!(count_orderings(&[1, 2, 3], &index), Some(0));
+        assert_eq!(Orderings::new(&[1, 2, 3], &index).next(), None);
+    }
+}
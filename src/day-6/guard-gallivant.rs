@@ -1,7 +1,8 @@
-use std::collections::HashSet;
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
 use std::io::{self, BufRead};
 use std::path::Path;
+use std::thread;
 
 fn load_lab_map(file_path: &Path) -> io::Result<Vec<String>> {
     let file = File::open(file_path)?;
@@ -9,128 +10,629 @@ fn load_lab_map(file_path: &Path) -> io::Result<Vec<String>> {
     lines.collect::<Result<Vec<_>, _>>()
 }
 
-fn find_guard(map: &[String]) -> ((usize, usize), char) {
-    for (y, row) in map.iter().enumerate() {
-        for (x, symbol) in row.chars().enumerate() {
-            if ['^', '>', 'v', '<'].contains(&symbol) {
-                return ((y, x), symbol);
-            }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Up,
+    Right,
+    Down,
+    Left,
+}
+
+impl Direction {
+    const ALL: [Direction; 4] = [
+        Direction::Up,
+        Direction::Right,
+        Direction::Down,
+        Direction::Left,
+    ];
+
+    fn from_symbol(symbol: char) -> Option<Self> {
+        match symbol {
+            '^' => Some(Direction::Up),
+            '>' => Some(Direction::Right),
+            'v' => Some(Direction::Down),
+            '<' => Some(Direction::Left),
+            _ => None,
         }
     }
-    panic!("Guard not found on the map");
-}
 
-fn turn_right(direction: char) -> char {
-    match direction {
-        '^' => '>',
-        '>' => 'v',
-        'v' => '<',
-        '<' => '^',
-        _ => panic!("Invalid direction"),
+    fn symbol(self) -> char {
+        match self {
+            Direction::Up => '^',
+            Direction::Right => '>',
+            Direction::Down => 'v',
+            Direction::Left => '<',
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
     }
+
+    fn is_vertical(self) -> bool {
+        matches!(self, Direction::Up | Direction::Down)
+    }
+
+    fn turn_right(self) -> Self {
+        Direction::ALL[(self.index() + 1) % 4]
+    }
+
+    fn turn_left(self) -> Self {
+        Direction::ALL[(self.index() + 3) % 4]
+    }
+
+    fn reverse(self) -> Self {
+        Direction::ALL[(self.index() + 2) % 4]
+    }
+
+    fn offset(self) -> (isize, isize) {
+        match self {
+            Direction::Up => (-1, 0),
+            Direction::Right => (0, 1),
+            Direction::Down => (1, 0),
+            Direction::Left => (0, -1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TurnPolicy {
+    Right,
+    Left,
+    Reverse,
+    // Right on odd turns, left on even ones
+    Alternating,
 }
 
-fn move_forward(position: (usize, usize), direction: char) -> (usize, usize) {
-    match direction {
-        '^' => (position.0.wrapping_sub(1), position.1),
-        '>' => (position.0, position.1 + 1),
-        'v' => (position.0 + 1, position.1),
-        '<' => (position.0, position.1.wrapping_sub(1)),
-        _ => panic!("Invalid direction"),
+impl TurnPolicy {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "right" => Some(TurnPolicy::Right),
+            "left" => Some(TurnPolicy::Left),
+            "reverse" => Some(TurnPolicy::Reverse),
+            "alternating" => Some(TurnPolicy::Alternating),
+            _ => None,
+        }
+    }
+
+    // How many distinct turn states a guard can be in, needed to tell real loops apart
+    fn phases(self) -> usize {
+        match self {
+            TurnPolicy::Alternating => 2,
+            _ => 1,
+        }
+    }
+
+    fn turn(self, direction: Direction, phase: usize) -> Direction {
+        match self {
+            TurnPolicy::Right => direction.turn_right(),
+            TurnPolicy::Left => direction.turn_left(),
+            TurnPolicy::Reverse => direction.reverse(),
+            TurnPolicy::Alternating if phase.is_multiple_of(2) => direction.turn_right(),
+            TurnPolicy::Alternating => direction.turn_left(),
+        }
     }
 }
 
-fn is_in_bounds(position: (usize, usize), map: &[String]) -> bool {
-    position.0 < map.len() && position.1 < map[0].len()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    y: usize,
+    x: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Guard {
+    position: Position,
+    direction: Direction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Exited,
+    Looped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Patrol {
+    // Distinct cells in the order they are first reached
+    visited: Vec<Position>,
+    outcome: Outcome,
 }
 
-fn is_obstacle(position: (usize, usize), map: &[String]) -> bool {
-    map[position.0].chars().nth(position.1).unwrap_or('.') == '#'
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PatrolReport {
+    patrols: Vec<Patrol>,
+    // Cells visited by more than one guard
+    crossings: Vec<Position>,
 }
 
-fn simulate_guard(map: &[String]) -> usize {
-    let ((mut y, mut x), mut direction) = find_guard(map);
-    let mut visited = HashSet::new();
-    visited.insert((y, x));
+#[derive(Debug)]
+struct Lab {
+    width: usize,
+    height: usize,
+    obstacles: Vec<bool>,
+    guards: Vec<Guard>,
+}
 
-    loop {
-        let next_position = move_forward((y, x), direction);
+impl Lab {
+    fn new(map: &[String]) -> Self {
+        let height = map.len();
+        let width = map.first().map_or(0, |row| row.len());
 
-        if !is_in_bounds(next_position, map) {
-            break;
+        let mut obstacles = vec![false; width * height];
+        let mut guards = Vec::new();
+        for (y, row) in map.iter().enumerate() {
+            for (x, symbol) in row.chars().enumerate() {
+                match symbol {
+                    '#' => obstacles[y * width + x] = true,
+                    _ => {
+                        if let Some(direction) = Direction::from_symbol(symbol) {
+                            let position = Position { y, x };
+                            guards.push(Guard {
+                                position,
+                                direction,
+                            });
+                        }
+                    }
+                }
+            }
         }
 
-        if is_obstacle(next_position, map) {
-            direction = turn_right(direction);
-        } else {
-            (y, x) = next_position;
-            visited.insert((y, x));
+        if guards.is_empty() {
+            panic!("Guard not found on the map");
+        }
+
+        Lab {
+            width,
+            height,
+            obstacles,
+            guards,
         }
     }
 
-    visited.len()
-}
+    // The puzzle itself is only about the first guard
+    fn guard(&self) -> Guard {
+        self.guards[0]
+    }
 
-fn simulate_with_obstruction(map: &[String], obstruction: Option<(usize, usize)>) -> bool {
-    let ((mut y, mut x), mut direction) = find_guard(map);
-    let mut visited_states = HashSet::new();
-    let mut obstruction_map = map.to_owned();
+    fn cell(&self, position: Position) -> usize {
+        position.y * self.width + position.x
+    }
+
+    fn position(&self, cell: usize) -> Position {
+        Position {
+            y: cell / self.width,
+            x: cell % self.width,
+        }
+    }
 
-    // Place the obstruction
-    if let Some((oy, ox)) = obstruction {
-        obstruction_map[oy].replace_range(ox..=ox, "#");
+    fn step(&self, position: Position, direction: Direction) -> Option<Position> {
+        let (dy, dx) = direction.offset();
+        let y = position
+            .y
+            .checked_add_signed(dy)
+            .filter(|&y| y < self.height)?;
+        let x = position
+            .x
+            .checked_add_signed(dx)
+            .filter(|&x| x < self.width)?;
+        Some(Position { y, x })
     }
 
-    loop {
-        let state = ((y, x), direction);
-        if visited_states.contains(&state) {
-            // Loop detected
-            return true;
+    fn is_obstacle(&self, position: Position) -> bool {
+        self.obstacles[self.cell(position)]
+    }
+
+    // Walks the guard and reports every (position, heading) state it passes through,
+    // with an optional extra obstruction placed on the map
+    fn walk(
+        &self,
+        guard: Guard,
+        policy: TurnPolicy,
+        obstruction: Option<Position>,
+        mut visit: impl FnMut(Position, Direction),
+    ) -> Outcome {
+        let Guard {
+            mut position,
+            mut direction,
+        } = guard;
+        let mut phase = 0;
+        let mut states = vec![false; self.obstacles.len() * 4 * policy.phases()];
+
+        visit(position, direction);
+        while let Some(next_position) = self.step(position, direction) {
+            if self.is_obstacle(next_position) || obstruction == Some(next_position) {
+                let state = (self.cell(position) * 4 + direction.index()) * policy.phases()
+                    + phase % policy.phases();
+                if states[state] {
+                    return Outcome::Looped;
+                }
+                states[state] = true;
+
+                direction = policy.turn(direction, phase);
+                phase += 1;
+            } else {
+                position = next_position;
+            }
+            visit(position, direction);
         }
-        visited_states.insert(state);
 
-        let next_position = move_forward((y, x), direction);
+        Outcome::Exited
+    }
+
+    fn patrol(&self, guard: Guard, policy: TurnPolicy) -> Patrol {
+        let mut seen = vec![false; self.obstacles.len()];
+        let mut visited = Vec::new();
+        let outcome = self.walk(guard, policy, None, |position, _| {
+            if !seen[self.cell(position)] {
+                seen[self.cell(position)] = true;
+                visited.push(position);
+            }
+        });
+
+        Patrol { visited, outcome }
+    }
+
+    fn patrol_path(&self) -> Vec<Position> {
+        self.patrol(self.guard(), TurnPolicy::Right).visited
+    }
+
+    fn patrol_all(&self, policy: TurnPolicy) -> PatrolReport {
+        let patrols = self
+            .guards
+            .iter()
+            .map(|&guard| self.patrol(guard, policy))
+            .collect::<Vec<_>>();
 
-        if !is_in_bounds(next_position, &obstruction_map) {
-            break;
+        let mut visits = vec![0usize; self.obstacles.len()];
+        for position in patrols.iter().flat_map(|patrol| &patrol.visited) {
+            visits[self.cell(*position)] += 1;
         }
+        let crossings = (0..visits.len())
+            .filter(|&cell| visits[cell] > 1)
+            .map(|cell| self.position(cell))
+            .collect();
+
+        PatrolReport { patrols, crossings }
+    }
+}
+
+// For every cell and direction, the cell where the guard stops in front of the next obstacle,
+// or `None` when walking that way leaves the lab
+struct JumpTable {
+    stops: Vec<[Option<usize>; 4]>,
+}
+
+impl JumpTable {
+    fn new(lab: &Lab) -> Self {
+        let mut stops = vec![[None; 4]; lab.obstacles.len()];
 
-        if is_obstacle(next_position, &obstruction_map) {
-            direction = turn_right(direction);
+        for direction in Direction::ALL {
+            // Visit cells so that the one ahead of each cell is always resolved first
+            let mut cells = (0..lab.obstacles.len()).collect::<Vec<_>>();
+            if matches!(direction, Direction::Down | Direction::Right) {
+                cells.reverse();
+            }
+
+            for cell in cells {
+                let ahead = lab.step(lab.position(cell), direction);
+                stops[cell][direction.index()] = match ahead {
+                    None => None,
+                    Some(ahead) if lab.is_obstacle(ahead) => Some(cell),
+                    Some(ahead) => stops[lab.cell(ahead)][direction.index()],
+                };
+            }
+        }
+
+        JumpTable { stops }
+    }
+
+    // Stop cell with a single extra obstruction patched in
+    fn stop(
+        &self,
+        lab: &Lab,
+        position: Position,
+        direction: Direction,
+        obstruction: Position,
+    ) -> Option<Position> {
+        let stop = self.stops[lab.cell(position)][direction.index()].map(|cell| lab.position(cell));
+
+        let ahead = match direction {
+            Direction::Up => obstruction.x == position.x && obstruction.y < position.y,
+            Direction::Down => obstruction.x == position.x && obstruction.y > position.y,
+            Direction::Left => obstruction.y == position.y && obstruction.x < position.x,
+            Direction::Right => obstruction.y == position.y && obstruction.x > position.x,
+        };
+        let before_stop = match stop {
+            None => true,
+            Some(stop) => {
+                obstruction.y.abs_diff(position.y) + obstruction.x.abs_diff(position.x)
+                    <= stop.y.abs_diff(position.y) + stop.x.abs_diff(position.x)
+            }
+        };
+
+        if ahead && before_stop {
+            lab.step(obstruction, direction.turn_right().turn_right())
         } else {
-            (y, x) = next_position;
+            stop
         }
     }
+}
 
-    false
+struct LoopDetector<'a> {
+    lab: &'a Lab,
+    jumps: &'a JumpTable,
+    // Generation stamp per (cell, direction) so the buffer never needs clearing between runs
+    stamps: Vec<u32>,
+    generation: u32,
 }
 
-fn find_possible_obstruction_positions(map: &[String]) -> usize {
-    let mut possible_positions = 0;
+impl<'a> LoopDetector<'a> {
+    fn new(lab: &'a Lab, jumps: &'a JumpTable) -> Self {
+        LoopDetector {
+            lab,
+            jumps,
+            stamps: vec![0; lab.obstacles.len() * 4],
+            generation: 0,
+        }
+    }
+
+    fn loops_with(&mut self, obstruction: Position) -> bool {
+        self.generation += 1;
+        let Guard {
+            mut position,
+            mut direction,
+        } = self.lab.guard();
 
-    for y in 0..map.len() {
-        for x in 0..map[0].len() {
-            if map[y].chars().nth(x).unwrap() == '.' {
-                // Test this position as an obstruction
-                if simulate_with_obstruction(map, Some((y, x))) {
-                    possible_positions += 1;
-                }
+        while let Some(stop) = self.jumps.stop(self.lab, position, direction, obstruction) {
+            let state = self.lab.cell(stop) * 4 + direction.index();
+            if self.stamps[state] == self.generation {
+                return true;
             }
+            self.stamps[state] = self.generation;
+
+            position = stop;
+            direction = direction.turn_right();
         }
+
+        false
     }
+}
+
+// Redraws the lab with the walked path, `|` and `-` for straight moves and `+` where the guard
+// turned or crossed its own path, `O` marks the extra obstruction
+fn render_path(lab: &Lab, guard: Guard, obstruction: Option<Position>) -> String {
+    const VERTICAL: u8 = 1;
+    const HORIZONTAL: u8 = 2;
+
+    let mut axes = vec![0u8; lab.obstacles.len()];
+    lab.walk(
+        guard,
+        TurnPolicy::Right,
+        obstruction,
+        |position, direction| {
+            axes[lab.cell(position)] |= if direction.is_vertical() {
+                VERTICAL
+            } else {
+                HORIZONTAL
+            };
+        },
+    );
+
+    let mut canvas = String::with_capacity((lab.width + 1) * lab.height);
+    for y in 0..lab.height {
+        for x in 0..lab.width {
+            let position = Position { y, x };
+            let symbol = if lab.is_obstacle(position) {
+                '#'
+            } else if obstruction == Some(position) {
+                'O'
+            } else if let Some(guard) = lab.guards.iter().find(|g| g.position == position) {
+                guard.direction.symbol()
+            } else {
+                match axes[lab.cell(position)] {
+                    VERTICAL => '|',
+                    HORIZONTAL => '-',
+                    0 => '.',
+                    _ => '+',
+                }
+            };
+            canvas.push(symbol);
+        }
+        canvas.push('\n');
+    }
+    canvas
+}
 
-    possible_positions
+// One frame per loop-inducing obstruction, each showing the loop it causes
+fn render_obstruction_loops(lab: &Lab) -> Vec<String> {
+    find_loop_obstructions(lab)
+        .into_iter()
+        .map(|obstruction| render_path(lab, lab.guard(), Some(obstruction)))
+        .collect()
+}
+
+fn simulate_guard(lab: &Lab) -> usize {
+    lab.patrol_path().len()
+}
+
+fn find_possible_obstruction_positions(lab: &Lab) -> usize {
+    find_loop_obstructions(lab).len()
+}
+
+fn find_loop_obstructions(lab: &Lab) -> Vec<Position> {
+    let jumps = JumpTable::new(lab);
+
+    // An obstruction off the original path is never touched, so it cannot change anything
+    let path = lab.patrol_path();
+    let candidates = &path[1..];
+
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = candidates.len().div_ceil(workers).max(1);
+
+    thread::scope(|scope| {
+        let handles = candidates
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let jumps = &jumps;
+                scope.spawn(move || {
+                    let mut detector = LoopDetector::new(lab, jumps);
+                    chunk
+                        .iter()
+                        .filter(|&&candidate| detector.loops_with(candidate))
+                        .copied()
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("worker thread panicked"))
+            .collect()
+    })
 }
 
 fn main() -> io::Result<()> {
     let file_path = Path::new("input.data");
     let lab_map = load_lab_map(file_path)?;
+    let lab = Lab::new(&lab_map);
 
-    let distinct_positions = simulate_guard(&lab_map);
+    let distinct_positions = simulate_guard(&lab);
     println!("Distinct positions visited: {}", distinct_positions);
 
-    let possible_positions = find_possible_obstruction_positions(&lab_map);
+    let possible_positions = find_possible_obstruction_positions(&lab);
     println!("Possible positions for obstruction: {}", possible_positions);
 
+    // `--render` draws the guard's path, `--render-loops [DIR]` draws every loop-inducing
+    // obstruction, either to stdout or as numbered frame files inside DIR
+    let args = env::args().collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--render") {
+        print!("{}", render_path(&lab, lab.guard(), None));
+    }
+    if let Some(index) = args.iter().position(|arg| arg == "--render-loops") {
+        let frames = render_obstruction_loops(&lab);
+        match args.get(index + 1).filter(|arg| !arg.starts_with("--")) {
+            Some(directory) => {
+                fs::create_dir_all(directory)?;
+                for (number, frame) in frames.iter().enumerate() {
+                    let frame_path = Path::new(directory).join(format!("frame-{:04}.txt", number));
+                    fs::write(frame_path, frame)?;
+                }
+                println!("Wrote {} frames to {}", frames.len(), directory);
+            }
+            None => {
+                for frame in &frames {
+                    println!("{}", frame);
+                }
+            }
+        }
+    }
+
+    // `--policy <right|left|reverse|alternating>` patrols every guard on the map with that rule
+    if let Some(index) = args.iter().position(|arg| arg == "--policy") {
+        let name = args.get(index + 1).map_or("", String::as_str);
+        let policy = TurnPolicy::from_name(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown turn policy: {:?}", name),
+            )
+        })?;
+
+        let report = lab.patrol_all(policy);
+        for (guard, patrol) in lab.guards.iter().zip(&report.patrols) {
+            println!(
+                "Guard at ({}, {}) facing {:?}: {} cells visited, {:?}",
+                guard.position.y,
+                guard.position.x,
+                guard.direction,
+                patrol.visited.len(),
+                patrol.outcome
+            );
+        }
+        println!("Cells where paths cross: {}", report.crossings.len());
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        find_possible_obstruction_positions, render_obstruction_loops, render_path, simulate_guard,
+        Lab, Outcome, Position, TurnPolicy,
+    };
+
+    fn example() -> Lab {
+        let map = include_str!("test.data")
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        Lab::new(&map)
+    }
+
+    #[test]
+    fn test_simulate_guard_example() {
+        assert_eq!(simulate_guard(&example()), 41);
+    }
+
+    #[test]
+    fn test_possible_obstructions_example() {
+        assert_eq!(find_possible_obstruction_positions(&example()), 6);
+    }
+
+    #[test]
+    fn test_turn_policies() {
+        let lab = example();
+        assert_eq!(
+            lab.patrol(lab.guard(), TurnPolicy::Right).outcome,
+            Outcome::Exited
+        );
+
+        // Bouncing straight back from the first obstacle walks out through the bottom
+        let patrol = lab.patrol(lab.guard(), TurnPolicy::Reverse);
+        assert_eq!(patrol.outcome, Outcome::Exited);
+        assert_eq!(patrol.visited.len(), 9);
+    }
+
+    #[test]
+    fn test_multiple_guards() {
+        let map = [".#...", "....#", ".^...", "#..<.", "...#."]
+            .map(String::from)
+            .to_vec();
+        let lab = Lab::new(&map);
+        assert_eq!(lab.guards.len(), 2);
+
+        let report = lab.patrol_all(TurnPolicy::Right);
+        assert_eq!(report.patrols[0].outcome, Outcome::Looped);
+        assert_eq!(report.patrols[1].outcome, Outcome::Looped);
+        assert!(report.crossings.contains(&Position { y: 3, x: 1 }));
+    }
+
+    #[test]
+    fn test_render_path_with_obstruction() {
+        let lab = example();
+        let rendered = render_path(&lab, lab.guard(), Some(Position { y: 6, x: 3 }));
+        assert_eq!(
+            rendered,
+            "....#.....\n\
+             ....+---+#\n\
+             ....|...|.\n\
+             ..#.|...|.\n\
+             ....|..#|.\n\
+             ....|...|.\n\
+             .#.O^---+.\n\
+             ........#.\n\
+             #.........\n\
+             ......#...\n"
+        );
+    }
+
+    #[test]
+    fn test_render_obstruction_loops() {
+        let lab = example();
+        let frames = render_obstruction_loops(&lab);
+        assert_eq!(frames.len(), 6);
+        assert!(frames.iter().any(|frame| frame.contains("#O+---+...")));
+    }
+}
@@ -1,43 +1,636 @@
+use num::bigint::BigUint;
+use num::integer::{Integer, Roots};
+use num::traits::{checked_pow, CheckedAdd, CheckedDiv, CheckedMul, CheckedSub};
+use num::{FromPrimitive, ToPrimitive};
+use std::cmp::Ordering;
+use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead};
+use std::ops::ControlFlow;
 use std::path::Path;
+use std::str::FromStr;
 use std::time::Instant;
 
-/// Enum for supported operators
-#[derive(Debug, Clone, Copy)]
-enum Operator {
-    Add,
-    Multiply,
-    Concatenate,
+/// Why an operator could not produce a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArithmeticError {
+    /// The result does not exist, e.g. dividing by zero or going below zero
+    Undefined,
+    /// The result exists but does not fit the integer type
+    Overflow,
 }
 
-impl Operator {
-    /// Parse a character into an `Operator`
-    fn from_char(c: char) -> Option<Operator> {
-        match c {
-            '+' => Some(Operator::Add),
-            '*' => Some(Operator::Multiply),
-            '|' => Some(Operator::Concatenate),
-            _ => None,
+impl fmt::Display for ArithmeticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithmeticError::Undefined => write!(f, "result is undefined"),
+            ArithmeticError::Overflow => write!(f, "result does not fit the integer type"),
         }
     }
+}
 
-    fn make_operator_list(operators: &str) -> Vec<Operator> {
-        operators
-            .chars()
-            .filter_map(Operator::from_char) // Convert each character to an Operator
+impl std::error::Error for ArithmeticError {}
+
+/// Unsigned integer type the solver can work with
+trait Number:
+    Integer
+    + Roots
+    + Clone
+    + fmt::Debug
+    + fmt::Display
+    + FromStr
+    + CheckedAdd
+    + CheckedSub
+    + CheckedMul
+    + CheckedDiv
+    + FromPrimitive
+    + ToPrimitive
+{
+    fn apply_operator(
+        operator: &dyn Operator,
+        lhs: &Self,
+        rhs: &Self,
+    ) -> Result<Self, ArithmeticError>;
+
+    fn undo_operator(
+        operator: &dyn Operator,
+        target: &Self,
+        operand: &Self,
+    ) -> Result<Preimage<Self>, ArithmeticError>;
+
+    /// Number of bits needed to write the value, zero for zero
+    fn bits(&self) -> u64;
+}
+
+impl Number for usize {
+    fn apply_operator(
+        operator: &dyn Operator,
+        lhs: &Self,
+        rhs: &Self,
+    ) -> Result<Self, ArithmeticError> {
+        Arithmetic::<usize>::apply(operator, lhs, rhs)
+    }
+
+    fn undo_operator(
+        operator: &dyn Operator,
+        target: &Self,
+        operand: &Self,
+    ) -> Result<Preimage<Self>, ArithmeticError> {
+        Arithmetic::<usize>::undo(operator, target, operand)
+    }
+
+    fn bits(&self) -> u64 {
+        u64::from(usize::BITS - self.leading_zeros())
+    }
+}
+
+impl Number for BigUint {
+    fn apply_operator(
+        operator: &dyn Operator,
+        lhs: &Self,
+        rhs: &Self,
+    ) -> Result<Self, ArithmeticError> {
+        Arithmetic::<BigUint>::apply(operator, lhs, rhs)
+    }
+
+    fn undo_operator(
+        operator: &dyn Operator,
+        target: &Self,
+        operand: &Self,
+    ) -> Result<Preimage<Self>, ArithmeticError> {
+        Arithmetic::<BigUint>::undo(operator, target, operand)
+    }
+
+    fn bits(&self) -> u64 {
+        BigUint::bits(self)
+    }
+}
+
+/// Every `lhs` for which `lhs <op> operand` gives a certain target
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Preimage<N> {
+    Values(Vec<N>),
+    /// Any left-hand side works, e.g. multiplying by zero to get zero
+    Any,
+}
+
+impl<N> Preimage<N> {
+    fn none() -> Self {
+        Preimage::Values(Vec::new())
+    }
+}
+
+/// How an operator computes with one integer type
+trait Arithmetic<N> {
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError>;
+
+    /// Find every `lhs` such that `lhs <op> operand == target`, only called for operators
+    /// that are `is_invertible`, the others keep this default
+    fn undo(&self, _target: &N, _operand: &N) -> Result<Preimage<N>, ArithmeticError> {
+        Ok(Preimage::none())
+    }
+}
+
+/// A binary operator that can be placed between two operands
+trait Operator: fmt::Debug + Send + Sync + Arithmetic<usize> + Arithmetic<BigUint> {
+    fn symbol(&self) -> String;
+
+    /// Binding strength when evaluating with standard precedence, higher binds tighter
+    fn precedence(&self) -> u8;
+
+    /// Whether `undo` gives few enough candidates to search backwards from the target, the
+    /// solver searches forwards otherwise
+    fn is_invertible(&self) -> bool {
+        false
+    }
+
+    /// Whether `a op b op c` means `a op (b op c)` when evaluating with standard precedence
+    fn is_right_associative(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+struct Add;
+
+impl Operator for Add {
+    fn symbol(&self) -> String {
+        "+".to_string()
+    }
+
+    fn precedence(&self) -> u8 {
+        1
+    }
+
+    fn is_invertible(&self) -> bool {
+        true
+    }
+}
+
+impl<N: Number> Arithmetic<N> for Add {
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError> {
+        lhs.checked_add(rhs).ok_or(ArithmeticError::Overflow)
+    }
+
+    fn undo(&self, target: &N, operand: &N) -> Result<Preimage<N>, ArithmeticError> {
+        Ok(Preimage::Values(
+            target.checked_sub(operand).into_iter().collect(),
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct Subtract;
+
+impl Operator for Subtract {
+    fn symbol(&self) -> String {
+        "-".to_string()
+    }
+
+    fn precedence(&self) -> u8 {
+        1
+    }
+
+    fn is_invertible(&self) -> bool {
+        true
+    }
+}
+
+impl<N: Number> Arithmetic<N> for Subtract {
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError> {
+        lhs.checked_sub(rhs).ok_or(ArithmeticError::Undefined)
+    }
+
+    fn undo(&self, target: &N, operand: &N) -> Result<Preimage<N>, ArithmeticError> {
+        let previous = target
+            .checked_add(operand)
+            .ok_or(ArithmeticError::Overflow)?;
+        Ok(Preimage::Values(vec![previous]))
+    }
+}
+
+#[derive(Debug)]
+struct Multiply;
+
+impl Operator for Multiply {
+    fn symbol(&self) -> String {
+        "*".to_string()
+    }
+
+    fn precedence(&self) -> u8 {
+        2
+    }
+
+    fn is_invertible(&self) -> bool {
+        true
+    }
+}
+
+impl<N: Number> Arithmetic<N> for Multiply {
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError> {
+        lhs.checked_mul(rhs).ok_or(ArithmeticError::Overflow)
+    }
+
+    fn undo(&self, target: &N, operand: &N) -> Result<Preimage<N>, ArithmeticError> {
+        if operand.is_zero() {
+            return Ok(if target.is_zero() {
+                Preimage::Any
+            } else {
+                Preimage::none()
+            });
+        }
+
+        let (quotient, remainder) = target.div_rem(operand);
+        Ok(if remainder.is_zero() {
+            Preimage::Values(vec![quotient])
+        } else {
+            Preimage::none()
+        })
+    }
+}
+
+/// Integer division rounding down
+#[derive(Debug)]
+struct Divide;
+
+impl Operator for Divide {
+    fn symbol(&self) -> String {
+        "/".to_string()
+    }
+
+    fn precedence(&self) -> u8 {
+        2
+    }
+
+    // Every division undoes to `operand` candidates, so searching backwards through a few of
+    // them multiplies the candidates out of hand, the solver always searches forwards instead
+}
+
+impl<N: Number> Arithmetic<N> for Divide {
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError> {
+        lhs.checked_div(rhs).ok_or(ArithmeticError::Undefined)
+    }
+}
+
+#[derive(Debug)]
+struct Power;
+
+impl Operator for Power {
+    fn symbol(&self) -> String {
+        "^".to_string()
+    }
+
+    fn precedence(&self) -> u8 {
+        3
+    }
+
+    fn is_invertible(&self) -> bool {
+        true
+    }
+
+    fn is_right_associative(&self) -> bool {
+        true
+    }
+}
+
+/// Largest power worth computing with big integers, in bits
+const MAX_POWER_BITS: u64 = 1 << 20;
+
+impl<N: Number> Arithmetic<N> for Power {
+    // Powers that would take more than `MAX_POWER_BITS` are refused before computing them,
+    // even big integers would spend minutes or run out of memory on e.g. `7 ^ 999999999`
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError> {
+        let exponent = rhs.to_u32().ok_or(ArithmeticError::Overflow)?;
+        // The power has at least `(bits - 1) * exponent + 1` bits, zero and one stay small
+        let lowest_bits = lhs.bits().saturating_sub(1) * u64::from(exponent);
+        if lowest_bits >= MAX_POWER_BITS {
+            return Err(ArithmeticError::Overflow);
+        }
+        checked_pow(lhs.clone(), exponent as usize).ok_or(ArithmeticError::Overflow)
+    }
+
+    fn undo(&self, target: &N, operand: &N) -> Result<Preimage<N>, ArithmeticError> {
+        if operand.is_zero() {
+            return Ok(if target.is_one() {
+                Preimage::Any
+            } else {
+                Preimage::none()
+            });
+        }
+
+        let exponent = operand.to_u32().ok_or(ArithmeticError::Overflow)?;
+        let root = target.nth_root(exponent);
+        Ok(if self.apply(&root, operand).as_ref() == Ok(target) {
+            Preimage::Values(vec![root])
+        } else {
+            Preimage::none()
+        })
+    }
+}
+
+/// Glues the digits of both operands together in the given base
+#[derive(Debug)]
+struct Concatenate {
+    base: usize,
+}
+
+impl Concatenate {
+    fn new(base: usize) -> Self {
+        assert!(base >= 2, "Concatenation base must be at least 2");
+        Concatenate { base }
+    }
+
+    /// Smallest power of the base above the operand
+    fn shift<N: Number>(&self, operand: &N) -> Result<N, ArithmeticError> {
+        let base = N::from_usize(self.base).ok_or(ArithmeticError::Overflow)?;
+        let mut shift = base.clone();
+        while shift <= *operand {
+            shift = shift.checked_mul(&base).ok_or(ArithmeticError::Overflow)?;
+        }
+        Ok(shift)
+    }
+}
+
+impl Operator for Concatenate {
+    fn symbol(&self) -> String {
+        match self.base {
+            10 => "||".to_string(),
+            base => format!("||{}", base),
+        }
+    }
+
+    // Concatenation joins two numerals, so nothing binds tighter
+    fn precedence(&self) -> u8 {
+        4
+    }
+
+    fn is_invertible(&self) -> bool {
+        true
+    }
+}
+
+impl<N: Number> Arithmetic<N> for Concatenate {
+    fn apply(&self, lhs: &N, rhs: &N) -> Result<N, ArithmeticError> {
+        lhs.checked_mul(&self.shift(rhs)?)
+            .and_then(|shifted| shifted.checked_add(rhs))
+            .ok_or(ArithmeticError::Overflow)
+    }
+
+    fn undo(&self, target: &N, operand: &N) -> Result<Preimage<N>, ArithmeticError> {
+        let (previous, suffix) = target.div_rem(&self.shift(operand)?);
+        Ok(if suffix == *operand {
+            Preimage::Values(vec![previous])
+        } else {
+            Preimage::none()
+        })
+    }
+}
+
+/// Operators available to equations, looked up by a single character key
+#[derive(Debug, Default)]
+struct OperatorRegistry {
+    operators: Vec<(char, Box<dyn Operator>)>,
+}
+
+impl OperatorRegistry {
+    fn standard() -> Self {
+        let mut registry = OperatorRegistry::default();
+        registry.register('+', Add);
+        registry.register('*', Multiply);
+        registry.register('|', Concatenate::new(10));
+        registry.register('-', Subtract);
+        registry.register('/', Divide);
+        registry.register('^', Power);
+        registry
+    }
+
+    /// Register an operator, replacing any previous one with the same key
+    fn register(&mut self, key: char, operator: impl Operator + 'static) {
+        self.operators.retain(|(existing, _)| *existing != key);
+        self.operators.push((key, Box::new(operator)));
+    }
+
+    fn make_operator_list(&self, keys: &str) -> Vec<&dyn Operator> {
+        keys.chars()
+            .filter_map(|key| {
+                self.operators
+                    .iter()
+                    .find(|(existing, _)| *existing == key)
+                    .map(|(_, operator)| operator.as_ref())
+            })
             .collect()
     }
 }
 
+/// How a chain of operators is evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Semantics {
+    /// Strictly left to right, as in the puzzle
+    LeftToRight,
+    /// Higher precedence first, equal precedence left to right
+    Precedence,
+}
+
+impl Semantics {
+    fn precedence(&self, operator: &dyn Operator) -> u8 {
+        match self {
+            Semantics::LeftToRight => 0,
+            Semantics::Precedence => operator.precedence(),
+        }
+    }
+
+    /// Whether `pending` has to be applied before `incoming` is pushed after it
+    fn binds_before(&self, pending: &dyn Operator, incoming: &dyn Operator) -> bool {
+        match self.precedence(pending).cmp(&self.precedence(incoming)) {
+            Ordering::Greater => true,
+            Ordering::Equal => *self == Semantics::LeftToRight || !incoming.is_right_associative(),
+            Ordering::Less => false,
+        }
+    }
+}
+
+/// Partially evaluated chain: operands still waiting for an operator of higher precedence
+#[derive(Debug, Clone)]
+struct Evaluation<'a, N> {
+    pending: Vec<(N, &'a dyn Operator)>,
+    current: N,
+}
+
+impl<'a, N: Number> Evaluation<'a, N> {
+    fn new(first: N) -> Self {
+        Evaluation {
+            pending: Vec::new(),
+            current: first,
+        }
+    }
+
+    fn push(
+        &self,
+        operator: &'a dyn Operator,
+        operand: &N,
+        semantics: Semantics,
+    ) -> Result<Self, ArithmeticError> {
+        let mut next = self.clone();
+        while let Some((lhs, pending)) = next.pending.last() {
+            if !semantics.binds_before(*pending, operator) {
+                break;
+            }
+            next.current = N::apply_operator(*pending, lhs, &next.current)?;
+            next.pending.pop();
+        }
+        next.pending.push((next.current, operator));
+        next.current = operand.clone();
+        Ok(next)
+    }
+
+    fn finish(&self) -> Result<N, ArithmeticError> {
+        self.pending
+            .iter()
+            .rev()
+            .try_fold(self.current.clone(), |rhs, (lhs, operator)| {
+                N::apply_operator(*operator, lhs, &rhs)
+            })
+    }
+}
+
+type Visitor<'v, 'a> = dyn FnMut(&[&'a dyn Operator]) -> ControlFlow<()> + 'v;
+
+/// One run of the solver over an equation, remembering whether any branch overflowed
+struct Search<'e, 'a, N> {
+    equation: &'e Equation<N>,
+    operators: &'e [&'a dyn Operator],
+    semantics: Semantics,
+    overflowed: bool,
+}
+
+impl<'a, N: Number> Search<'_, 'a, N> {
+    fn run(&mut self, visit: &mut Visitor<'_, 'a>) -> ControlFlow<()> {
+        let backwards = self.semantics == Semantics::LeftToRight
+            && self
+                .operators
+                .iter()
+                .all(|operator| operator.is_invertible());
+
+        let operands = &self.equation.operands;
+        let last = operands.len() - 1;
+        if backwards {
+            let target = self.equation.value.clone();
+            self.backward(target, last, &mut Vec::new(), visit)
+        } else {
+            let target = self.equation.value.clone();
+            let first = Evaluation::new(operands[0].clone());
+            self.forward(
+                last,
+                &|value| *value == target,
+                &mut Vec::new(),
+                first,
+                visit,
+            )
+        }
+    }
+
+    fn note<T>(&mut self, result: Result<T, ArithmeticError>) -> Option<T> {
+        if result.as_ref().err() == Some(&ArithmeticError::Overflow) {
+            self.overflowed = true;
+        }
+        result.ok()
+    }
+
+    /// Works backwards from the value, undoing one operator per operand from the right,
+    /// so branches that cannot lead to the value are cut as early as possible.
+    /// `suffix` holds the operators chosen so far, rightmost first.
+    fn backward(
+        &mut self,
+        target: N,
+        index: usize,
+        suffix: &mut Vec<&'a dyn Operator>,
+        visit: &mut Visitor<'_, 'a>,
+    ) -> ControlFlow<()> {
+        let equation = self.equation;
+        if index == 0 {
+            if target == equation.operands[0] {
+                let solution = suffix.iter().rev().copied().collect::<Vec<_>>();
+                return visit(&solution);
+            }
+            return ControlFlow::Continue(());
+        }
+
+        for &operator in self.operators {
+            let preimage = N::undo_operator(operator, &target, &equation.operands[index]);
+            suffix.push(operator);
+            match self.note(preimage) {
+                Some(Preimage::Values(values)) => {
+                    for previous in values {
+                        self.backward(previous, index - 1, suffix, visit)?;
+                    }
+                }
+                Some(Preimage::Any) => {
+                    // Every way of evaluating the operands on the left will do
+                    let tail = suffix.iter().rev().copied().collect::<Vec<_>>();
+                    let first = Evaluation::new(equation.operands[0].clone());
+                    self.forward(
+                        index - 1,
+                        &|_| true,
+                        &mut Vec::new(),
+                        first,
+                        &mut |prefix| {
+                            let solution = prefix.iter().chain(&tail).copied().collect::<Vec<_>>();
+                            visit(&solution)
+                        },
+                    )?;
+                }
+                None => {}
+            }
+            suffix.pop();
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Tries every operator between `operands[..=last]` from the left
+    fn forward(
+        &mut self,
+        last: usize,
+        accept: &dyn Fn(&N) -> bool,
+        chosen: &mut Vec<&'a dyn Operator>,
+        evaluation: Evaluation<'a, N>,
+        visit: &mut Visitor<'_, 'a>,
+    ) -> ControlFlow<()> {
+        let index = chosen.len() + 1;
+        if index > last {
+            return match self.note(evaluation.finish()) {
+                Some(value) if accept(&value) => visit(chosen),
+                _ => ControlFlow::Continue(()),
+            };
+        }
+
+        for &operator in self.operators {
+            let operand = &self.equation.operands[index];
+            let next = evaluation.push(operator, operand, self.semantics);
+            if let Some(next) = self.note(next) {
+                chosen.push(operator);
+                let flow = self.forward(last, accept, chosen, next, visit);
+                chosen.pop();
+                flow?;
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
 #[derive(Debug)]
-struct Equation {
-    value: usize,
-    operands: Vec<usize>,
+struct Equation<N> {
+    value: N,
+    operands: Vec<N>,
 }
 
-impl Equation {
-    fn parse(input: &str) -> Equation {
+impl<N: Number> Equation<N>
+where
+    N::Err: fmt::Debug,
+{
+    fn parse(input: &str) -> Equation<N> {
         let parts: Vec<&str> = input.split(':').collect();
         assert_eq!(
             parts.len(),
@@ -47,56 +640,154 @@ impl Equation {
 
         let value = parts[0]
             .trim()
-            .parse::<usize>()
+            .parse::<N>()
             .expect("Invalid value in input");
 
         let operands = parts[1]
-            .trim()
             .split_whitespace()
             .map(|operand| {
                 operand
                     .trim()
-                    .parse::<usize>()
+                    .parse::<N>()
                     .expect("Invalid operand in input")
             })
             .collect();
 
         Equation { value, operands }
     }
+}
 
-    fn validate(&self, operators: &[Operator]) -> bool {
-        let num_operators = self.operands.len() - 1;
+impl<N: Number> Equation<N> {
+    /// Find operators that turn the operands into the value.
+    /// Fails with `Overflow` when nothing was found but some branches could not be computed.
+    fn solve<'a>(
+        &self,
+        operators: &[&'a dyn Operator],
+        semantics: Semantics,
+    ) -> Result<Option<Vec<&'a dyn Operator>>, ArithmeticError> {
+        let mut found = None;
+        self.search(operators, semantics, &mut |solution| {
+            found = Some(solution.to_vec());
+            ControlFlow::Break(())
+        })?;
+        Ok(found)
+    }
 
-        // Generate all possible operator combinations
-        let mut combinations = (0..operators.len().pow(num_operators as u32)).map(|bitmask| {
-            (0..num_operators)
-                .map(|i| operators[(bitmask / operators.len().pow(i as u32)) % operators.len()])
-                .collect::<Vec<Operator>>()
-        });
+    /// Count every operator assignment that turns the operands into the value
+    fn count_solutions(
+        &self,
+        operators: &[&dyn Operator],
+        semantics: Semantics,
+    ) -> Result<usize, ArithmeticError> {
+        let mut count = 0;
+        self.search(operators, semantics, &mut |_| {
+            count += 1;
+            ControlFlow::Continue(())
+        })?;
+        Ok(count)
+    }
 
-        combinations.any(|ops| self.evaluate(&ops) == self.value)
+    fn search<'a>(
+        &self,
+        operators: &[&'a dyn Operator],
+        semantics: Semantics,
+        visit: &mut Visitor<'_, 'a>,
+    ) -> Result<(), ArithmeticError> {
+        let mut search = Search {
+            equation: self,
+            operators,
+            semantics,
+            overflowed: false,
+        };
+        match search.run(visit) {
+            ControlFlow::Continue(()) if search.overflowed => Err(ArithmeticError::Overflow),
+            _ => Ok(()),
+        }
     }
 
-    fn evaluate(&self, operators: &[Operator]) -> usize {
-        let mut result = self.operands[0];
+    fn evaluate(
+        &self,
+        operators: &[&dyn Operator],
+        semantics: Semantics,
+    ) -> Result<N, ArithmeticError> {
+        operators
+            .iter()
+            .zip(&self.operands[1..])
+            .try_fold(
+                Evaluation::new(self.operands[0].clone()),
+                |evaluation, (&operator, operand)| evaluation.push(operator, operand, semantics),
+            )?
+            .finish()
+    }
 
-        for (i, &operator) in operators.iter().enumerate() {
-            match operator {
-                Operator::Add => result += self.operands[i + 1],
-                Operator::Multiply => result *= self.operands[i + 1],
-                Operator::Concatenate => {
-                    let rhs = self.operands[i + 1];
-                    let rhs_digit_count = (rhs as f64).log10().floor() as u32 + 1;
-                    result = (result * 10_usize.pow(rhs_digit_count)) + self.operands[i + 1];
-                }
-            }
+    /// Render a solution as e.g. `292 = 11 + 6 * 16 + 20`
+    fn format_solution(&self, operators: &[&dyn Operator]) -> String {
+        let mut text = format!("{} = {}", self.value, self.operands[0]);
+        for (operator, operand) in operators.iter().zip(&self.operands[1..]) {
+            text.push_str(&format!(" {} {}", operator.symbol(), operand));
+        }
+        text
+    }
+}
+
+impl Equation<BigUint> {
+    /// The same equation with machine integers, if every number fits
+    fn narrow(&self) -> Option<Equation<usize>> {
+        Some(Equation {
+            value: self.value.to_usize()?,
+            operands: self
+                .operands
+                .iter()
+                .map(|operand| operand.to_usize())
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    /// Solve with machine integers, switching to big integers
+    /// when the numbers or any intermediate result do not fit
+    fn solve_exact<'a>(
+        &self,
+        operators: &[&'a dyn Operator],
+        semantics: Semantics,
+    ) -> Option<Vec<&'a dyn Operator>> {
+        if let Some(Ok(solution)) = self
+            .narrow()
+            .map(|equation| equation.solve(operators, semantics))
+        {
+            return solution;
+        }
+        self.solve(operators, semantics).ok().flatten()
+    }
+
+    fn count_solutions_exact(
+        &self,
+        operators: &[&dyn Operator],
+        semantics: Semantics,
+    ) -> Result<usize, ArithmeticError> {
+        if let Some(Ok(count)) = self
+            .narrow()
+            .map(|equation| equation.count_solutions(operators, semantics))
+        {
+            return Ok(count);
         }
+        self.count_solutions(operators, semantics)
+    }
 
-        result
+    fn validate(&self, operators: &[&dyn Operator], semantics: Semantics) -> bool {
+        match self.solve_exact(operators, semantics) {
+            Some(solution) => {
+                debug_assert_eq!(
+                    self.evaluate(&solution, semantics).as_ref(),
+                    Ok(&self.value)
+                );
+                true
+            }
+            None => false,
+        }
     }
 }
 
-fn load_calibration_equations(file_path: &Path) -> io::Result<Vec<Equation>> {
+fn load_calibration_equations(file_path: &Path) -> io::Result<Vec<Equation<BigUint>>> {
     let file = File::open(file_path)?;
     let lines = io::BufReader::new(file).lines();
     lines
@@ -107,29 +798,285 @@ fn load_calibration_equations(file_path: &Path) -> io::Result<Vec<Equation>> {
         .collect()
 }
 
-fn get_calibration_equations(equations: &Vec<Equation>, operators: &[Operator]) -> usize {
+fn get_calibration_equations(
+    equations: &[Equation<BigUint>],
+    operators: &[&dyn Operator],
+    semantics: Semantics,
+) -> BigUint {
     equations
         .iter()
-        .filter(|equation| equation.validate(operators))
-        .map(|equation| equation.value)
+        .filter(|equation| equation.validate(operators, semantics))
+        .map(|equation| &equation.value)
         .sum()
 }
 
 fn main() -> io::Result<()> {
     let file_path = Path::new("input.data");
     let calibration_equations = load_calibration_equations(file_path)?;
+    let registry = OperatorRegistry::standard();
+    let args = env::args().collect::<Vec<_>>();
 
-    let timer = Instant::now();
-    let basic_operators = Operator::make_operator_list("+*");
-    let calibration_value = get_calibration_equations(&calibration_equations, &basic_operators);
-    println!("Calibration value (+*): {}", calibration_value);
-    println!("Time elapsed: {:?}", timer.elapsed());
+    // `--precedence` evaluates with standard operator precedence instead of left to right
+    let semantics = if args.iter().any(|arg| arg == "--precedence") {
+        Semantics::Precedence
+    } else {
+        Semantics::LeftToRight
+    };
+
+    // `--operators <keys>` checks an extra operator set, e.g. `--operators +-*/`
+    let mut operator_sets = vec!["+*".to_string(), "+*|".to_string()];
+    if let Some(index) = args.iter().position(|arg| arg == "--operators") {
+        operator_sets.extend(args.get(index + 1).cloned());
+    }
+
+    for keys in &operator_sets {
+        let timer = Instant::now();
+        let operators = registry.make_operator_list(keys);
+        let calibration_value =
+            get_calibration_equations(&calibration_equations, &operators, semantics);
+        println!("Calibration value ({}): {}", keys, calibration_value);
+        println!("Time elapsed: {:?}", timer.elapsed());
+    }
 
-    let timer = Instant::now();
-    let extended_operators = Operator::make_operator_list("+*|");
-    let calibration_value = get_calibration_equations(&calibration_equations, &extended_operators);
-    println!("Calibration value (+*|): {}", calibration_value);
-    println!("Time elapsed: {:?}", timer.elapsed());
+    // `--explain` prints the operators found for every equation that can be made true
+    if args.iter().any(|arg| arg == "--explain") {
+        let extended_operators = registry.make_operator_list("+*|");
+        for equation in &calibration_equations {
+            if let Some(solution) = equation.solve_exact(&extended_operators, semantics) {
+                let text = equation.format_solution(&solution);
+                match equation.count_solutions_exact(&extended_operators, semantics) {
+                    Ok(count) if count > 1 => println!("{} (+{} more)", text, count - 1),
+                    Ok(_) => println!("{}", text),
+                    Err(error) => println!("{} (could not count the others: {})", text, error),
+                }
+            }
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        get_calibration_equations, ArithmeticError, Concatenate, Equation, Evaluation,
+        OperatorRegistry, Search, Semantics,
+    };
+    use num::bigint::BigUint;
+    use num::ToPrimitive;
+    use std::ops::ControlFlow;
+
+    fn example() -> Vec<Equation<BigUint>> {
+        include_str!("test.data")
+            .lines()
+            .map(Equation::parse)
+            .collect()
+    }
+
+    #[test]
+    fn test_example_calibration() {
+        let equations = example();
+        let registry = OperatorRegistry::standard();
+        let basic_operators = registry.make_operator_list("+*");
+        let extended_operators = registry.make_operator_list("+*|");
+
+        for semantics in [Semantics::LeftToRight, Semantics::Precedence] {
+            let basic = get_calibration_equations(&equations, &basic_operators, semantics);
+            let extended = get_calibration_equations(&equations, &extended_operators, semantics);
+            match semantics {
+                Semantics::LeftToRight => {
+                    assert_eq!(basic, BigUint::from(3749u32));
+                    assert_eq!(extended, BigUint::from(11387u32));
+                }
+                Semantics::Precedence => {
+                    assert_eq!(basic, BigUint::from(3457u32));
+                    assert_eq!(extended, BigUint::from(3805u32));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_division_searches_forwards() {
+        let registry = OperatorRegistry::standard();
+        let operators = registry.make_operator_list("+*/");
+
+        let mut total = 0u32;
+        for equation in example() {
+            let mut search = Search {
+                equation: &equation,
+                operators: &operators,
+                semantics: Semantics::LeftToRight,
+                overflowed: false,
+            };
+            let flow = search.run(&mut |_| ControlFlow::Break(()));
+            if flow.is_break() {
+                total += equation.value.to_u32().unwrap();
+            }
+        }
+        assert_eq!(total, 3749);
+    }
+
+    #[test]
+    fn test_solution_witness() {
+        let registry = OperatorRegistry::standard();
+        let operators = registry.make_operator_list("+*|");
+
+        let equation = Equation::<usize>::parse("292: 11 6 16 20");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(
+            equation.format_solution(&solution),
+            "292 = 11 + 6 * 16 + 20"
+        );
+
+        let equation = Equation::<usize>::parse("7290: 6 8 6 15");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(
+            equation.format_solution(&solution),
+            "7290 = 6 * 8 || 6 * 15"
+        );
+
+        let equation = Equation::<usize>::parse("83: 17 5");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        assert!(matches!(solution, Ok(None)));
+    }
+
+    #[test]
+    fn test_multiply_by_zero() {
+        let registry = OperatorRegistry::standard();
+        let operators = registry.make_operator_list("+*");
+        let equation = Equation::<usize>::parse("0: 7 3 0");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(equation.evaluate(&solution, Semantics::LeftToRight), Ok(0));
+        assert_eq!(
+            equation.count_solutions(&operators, Semantics::LeftToRight),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn test_precedence() {
+        let registry = OperatorRegistry::standard();
+        let operators = registry.make_operator_list("+*");
+
+        // 2 + 3 * 4 is 20 left to right but 14 with precedence
+        let equation = Equation::<usize>::parse("14: 2 3 4");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        assert!(matches!(solution, Ok(None)));
+        let solution = equation.solve(&operators, Semantics::Precedence);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(equation.format_solution(&solution), "14 = 2 + 3 * 4");
+    }
+
+    #[test]
+    fn test_extra_operators() {
+        let mut registry = OperatorRegistry::standard();
+        registry.register('b', Concatenate::new(2));
+        let operators = registry.make_operator_list("-/^b");
+
+        let equation = Equation::<usize>::parse("4: 20 3 2");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(equation.format_solution(&solution), "4 = 20 / 3 - 2");
+
+        // Left to right `2 ^ 3 ^ 2` is `(2 ^ 3) ^ 2`, with precedence it is `2 ^ (3 ^ 2)`
+        let equation = Equation::<usize>::parse("64: 2 3 2");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(equation.format_solution(&solution), "64 = 2 ^ 3 ^ 2");
+        assert_eq!(equation.evaluate(&solution, Semantics::Precedence), Ok(512));
+        let solution = equation.solve(&operators, Semantics::Precedence);
+        assert!(matches!(solution, Ok(None)));
+
+        let equation = Equation::<usize>::parse("512: 2 3 2");
+        let solution = equation.solve(&operators, Semantics::Precedence);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(equation.format_solution(&solution), "512 = 2 ^ 3 ^ 2");
+
+        // Only powers group from the right, `2 * 3 ^ 2 ^ 1 - 4` is `2 * 3 ^ (2 ^ 1) - 4`
+        let equation = Equation::<usize>::parse("14: 2 3 2 1 4");
+        let chosen = registry.make_operator_list("*^^-");
+        assert_eq!(equation.evaluate(&chosen, Semantics::Precedence), Ok(14));
+
+        // Huge powers are refused instead of computed, even with big integers
+        let equation = Equation::<BigUint>::parse("7: 7 999999999");
+        let power = registry.make_operator_list("^");
+        assert_eq!(
+            equation.evaluate(&power, Semantics::LeftToRight),
+            Err(ArithmeticError::Overflow)
+        );
+        assert_eq!(
+            equation.count_solutions_exact(&operators, Semantics::LeftToRight),
+            Err(ArithmeticError::Overflow)
+        );
+        let equation = Equation::<BigUint>::parse("1: 1 999999999");
+        assert_eq!(
+            equation.count_solutions_exact(&power, Semantics::LeftToRight),
+            Ok(1)
+        );
+
+        // 0b101 || 0b11 == 0b10111
+        let equation = Equation::<usize>::parse("23: 5 3");
+        let solution = equation.solve(&operators, Semantics::LeftToRight);
+        let solution = solution.unwrap().unwrap();
+        assert_eq!(equation.format_solution(&solution), "23 = 5 ||2 3");
+    }
+
+    #[test]
+    fn test_counts_agree_between_searches() {
+        let registry = OperatorRegistry::standard();
+        let operators = registry.make_operator_list("+*|");
+
+        for equation in example() {
+            let backward = equation.count_solutions(&operators, Semantics::LeftToRight);
+            let mut forward = 0;
+            let mut search = Search {
+                equation: &equation,
+                operators: &operators,
+                semantics: Semantics::LeftToRight,
+                overflowed: false,
+            };
+            let _ = search.forward(
+                equation.operands.len() - 1,
+                &|value| *value == equation.value,
+                &mut Vec::new(),
+                Evaluation::new(equation.operands[0].clone()),
+                &mut |_| {
+                    forward += 1;
+                    ControlFlow::Continue(())
+                },
+            );
+            assert_eq!(backward, Ok(forward));
+        }
+    }
+
+    #[test]
+    fn test_big_numbers() {
+        let registry = OperatorRegistry::standard();
+        let operators = registry.make_operator_list("+*|");
+
+        // Too large to parse as a machine integer
+        let equations = [
+            "100000000000000000000: 10000000000 10000000000",
+            "123456789012345678901234567890: 123456789012345 678901234567890",
+        ]
+        .map(Equation::<BigUint>::parse);
+        assert!(equations[0].narrow().is_none());
+        let total = get_calibration_equations(&equations, &operators, Semantics::LeftToRight);
+        let expected = "123456789112345678901234567890".parse::<BigUint>().unwrap();
+        assert_eq!(total, expected);
+
+        // Fits, but only big integers can evaluate `a * a * 0` and `a || a * 0`
+        let equation = Equation::<BigUint>::parse("0: 10000000000 10000000000 0");
+        let narrow = equation.narrow().unwrap();
+        let solution = narrow.solve(&operators, Semantics::Precedence);
+        assert!(matches!(solution, Err(ArithmeticError::Overflow)));
+        assert_eq!(
+            equation.count_solutions_exact(&operators, Semantics::Precedence),
+            Ok(2)
+        );
+    }
+}
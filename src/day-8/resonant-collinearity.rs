@@ -1,115 +1,374 @@
+use num::integer::{gcd, Integer};
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, Read};
 use std::path::Path;
 use std::time::Instant;
 
+/// Position of a cell, flat maps keep every cell on layer `z = 0`
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 struct Coordinate {
     x: i32,
     y: i32,
+    z: i32,
 }
 
 impl fmt::Debug for Coordinate {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "({}, {})", self.x, self.y)
+        match self.z {
+            0 => write!(f, "({}, {})", self.x, self.y),
+            z => write!(f, "({}, {}, {})", self.x, self.y, z),
+        }
+    }
+}
+
+/// Cells of the field where antinodes can appear
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Region {
+    /// Axis-aligned box from `min` (inclusive) to `max` (exclusive), may lie anywhere
+    Box { min: Coordinate, max: Coordinate },
+    /// Only the listed cells
+    Mask(HashSet<Coordinate>),
+    /// Cells at most `radius` away from `center` in straight-line distance
+    Radius { center: Coordinate, radius: i32 },
+}
+
+impl Region {
+    /// Mask with a valid cell for every `#`, slices are separated by blank lines like the map
+    fn parse_mask(input: &str) -> Region {
+        Region::Mask(
+            parse_layers(input)
+                .filter(|&(_, cell)| cell == '#')
+                .map(|(position, _)| position)
+                .collect(),
+        )
     }
+
+    fn contains(&self, position: &Coordinate) -> bool {
+        match self {
+            Region::Box { min, max } => {
+                (min.x..max.x).contains(&position.x)
+                    && (min.y..max.y).contains(&position.y)
+                    && (min.z..max.z).contains(&position.z)
+            }
+            Region::Mask(cells) => cells.contains(position),
+            Region::Radius { center, radius } => {
+                let (dx, dy, dz) = (
+                    (position.x - center.x) as i64,
+                    (position.y - center.y) as i64,
+                    (position.z - center.z) as i64,
+                );
+                dx * dx + dy * dy + dz * dz <= (*radius as i64) * (*radius as i64)
+            }
+        }
+    }
+
+    /// Smallest box holding the whole region, as `(min, max)` with `max` exclusive,
+    /// `None` if the box reaches past the coordinates an `i32` can hold
+    fn bounds(&self) -> Option<(Coordinate, Coordinate)> {
+        let bounds = match self {
+            Region::Box { min, max } => (*min, *max),
+            Region::Mask(cells) if cells.is_empty() => {
+                let origin = Coordinate { x: 0, y: 0, z: 0 };
+                (origin, origin)
+            }
+            Region::Mask(cells) => {
+                let (mut min, mut max) = (
+                    Coordinate {
+                        x: i32::MAX,
+                        y: i32::MAX,
+                        z: i32::MAX,
+                    },
+                    Coordinate {
+                        x: i32::MIN,
+                        y: i32::MIN,
+                        z: i32::MIN,
+                    },
+                );
+                for cell in cells {
+                    min = Coordinate {
+                        x: min.x.min(cell.x),
+                        y: min.y.min(cell.y),
+                        z: min.z.min(cell.z),
+                    };
+                    max = Coordinate {
+                        x: max.x.max(cell.x.checked_add(1)?),
+                        y: max.y.max(cell.y.checked_add(1)?),
+                        z: max.z.max(cell.z.checked_add(1)?),
+                    };
+                }
+                (min, max)
+            }
+            Region::Radius { center, radius } => {
+                let above = radius.checked_add(1)?;
+                (
+                    Coordinate {
+                        x: center.x.checked_sub(*radius)?,
+                        y: center.y.checked_sub(*radius)?,
+                        z: center.z.checked_sub(*radius)?,
+                    },
+                    Coordinate {
+                        x: center.x.checked_add(above)?,
+                        y: center.y.checked_add(above)?,
+                        z: center.z.checked_add(above)?,
+                    },
+                )
+            }
+        };
+        Some(bounds)
+    }
+}
+
+/// Every cell of a map made of slices separated by blank lines, a single slice is a flat map
+fn parse_layers(input: &str) -> impl Iterator<Item = (Coordinate, char)> + '_ {
+    input
+        .trim_end()
+        .split("\n\n")
+        .enumerate()
+        .flat_map(|(z, layer)| {
+            layer.lines().enumerate().flat_map(move |(y, line)| {
+                line.chars().enumerate().map(move |(x, ch)| {
+                    let position = Coordinate {
+                        x: x as i32,
+                        y: y as i32,
+                        z: z as i32,
+                    };
+                    (position, ch)
+                })
+            })
+        })
 }
 
 struct Map {
-    width: i32,
-    height: i32,
+    /// Slices of the parsed field, a flat map has a single one
+    layers: Vec<Vec<Vec<char>>>,
     antennas: HashMap<char, Vec<Coordinate>>,
+    region: Region,
+    bounds: (Coordinate, Coordinate),
 }
 
 impl Map {
     fn load(file_path: &Path) -> io::Result<Map> {
-        let file = File::open(file_path)?;
-        let lines: Vec<_> = io::BufReader::new(file).lines().collect::<Result<_, _>>()?;
+        let mut input = String::new();
+        File::open(file_path)?.read_to_string(&mut input)?;
+        Ok(Map::parse(&input))
+    }
+
+    fn parse(input: &str) -> Map {
+        let layers: Vec<Vec<Vec<char>>> = input
+            .trim_end()
+            .split("\n\n")
+            .map(|layer| layer.lines().map(|line| line.chars().collect()).collect())
+            .collect();
 
         let mut antennas: HashMap<char, Vec<Coordinate>> = HashMap::new();
-        let height = lines.len() as i32;
-        let width = lines[0].len() as i32;
-
-        for (y, line) in lines.iter().enumerate() {
-            for (x, ch) in line.chars().enumerate() {
-                if ch != '.' {
-                    antennas
-                        .entry(ch)
-                        .or_insert_with(Vec::new)
-                        .push(Coordinate {
-                            x: x as i32,
-                            y: y as i32,
-                        });
-                }
+        for (position, ch) in parse_layers(input) {
+            if ch != '.' {
+                antennas.entry(ch).or_default().push(position);
             }
         }
 
-        Ok(Map {
-            width,
-            height,
+        // By default the field is exactly the parsed box
+        let min = Coordinate { x: 0, y: 0, z: 0 };
+        let max = Coordinate {
+            x: layers[0][0].len() as i32,
+            y: layers[0].len() as i32,
+            z: layers.len() as i32,
+        };
+
+        Map {
+            layers,
             antennas,
-        })
+            region: Region::Box { min, max },
+            bounds: (min, max),
+        }
+    }
+
+    /// The same map with antinodes limited to another region,
+    /// `None` if the region reaches past the coordinates an `i32` can hold
+    fn with_region(mut self, region: Region) -> Option<Map> {
+        self.bounds = region.bounds()?;
+        self.region = region;
+        Some(self)
     }
 
     fn is_in_bound(&self, position: &Coordinate) -> bool {
-        position.x >= 0 && position.y >= 0 && position.x < self.width && position.y < self.height
+        self.region.contains(position)
+    }
+
+    fn cell(&self, position: &Coordinate) -> Option<char> {
+        let layer = self.layers.get(usize::try_from(position.z).ok()?)?;
+        let row = layer.get(usize::try_from(position.y).ok()?)?;
+        row.get(usize::try_from(position.x).ok()?).copied()
+    }
+
+    fn frequencies(&self) -> Vec<char> {
+        let mut frequencies = self.antennas.keys().copied().collect::<Vec<_>>();
+        frequencies.sort_unstable();
+        frequencies
+    }
+
+    fn antennas_of(&self, frequency: char) -> &[Coordinate] {
+        self.antennas.get(&frequency).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// How to walk along the line through two antennas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stepping {
+    /// Step by the spacing of the `k:1` antinodes, `1 / (k - 1)` of the distance between the
+    /// antennas, stretched as little as needed to land on the grid
+    Raw,
+    /// Step by the distance divided by `gcd(dx, dy, dz)`, so no grid position on the line is skipped
+    Reduced,
+}
+
+/// Which positions count as antinodes of a pair of antennas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AntinodeRule {
+    /// Positions in line with both antennas and outside the pair where one of them is exactly
+    /// `k` times as far away as the other, `Ratio(2)` is the original rule
+    Ratio(i32),
+    /// Like `Ratio`, plus the positions between the antennas with the same distance ratio
+    RatioWithin(i32),
+    /// Every position in line with both antennas, taking resonant harmonics into account.
+    /// The harmonics repeat the `k:1` antinodes, so `Line(Stepping::Raw, 2)` is the original
+    /// rule and a ratio of 1, with no outer antinodes to repeat, leaves no grid position out
+    Line(Stepping, i32),
+}
+
+impl AntinodeRule {
+    fn antinodes(&self, map: &Map, lhs: Coordinate, rhs: Coordinate) -> HashSet<Coordinate> {
+        match *self {
+            AntinodeRule::Ratio(k) => get_ratio_antinodes(map, lhs, rhs, k, false),
+            AntinodeRule::RatioWithin(k) => get_ratio_antinodes(map, lhs, rhs, k, true),
+            AntinodeRule::Line(stepping, k) => get_all_antinodes(map, lhs, rhs, stepping, k),
+        }
     }
 }
 
-fn get_first_antinodes(map: &Map, lhs: Coordinate, rhs: Coordinate) -> HashSet<Coordinate> {
-    let dx = rhs.x - lhs.x;
-    let dy = rhs.y - lhs.y;
+/// Point at `numerator / denominator` of the way from `from` to `to`, if it lies on the grid
+/// and its coordinates fit an `i32`
+fn lattice_point(
+    from: Coordinate,
+    to: Coordinate,
+    numerator: i32,
+    denominator: i32,
+) -> Option<Coordinate> {
+    let dx = (to.x - from.x).checked_mul(numerator)?;
+    let dy = (to.y - from.y).checked_mul(numerator)?;
+    let dz = (to.z - from.z).checked_mul(numerator)?;
+    if denominator == 0 || [dx, dy, dz].iter().any(|delta| delta % denominator != 0) {
+        return None;
+    }
 
-    let first = Coordinate {
-        x: lhs.x - dx,
-        y: lhs.y - dy,
-    };
+    Some(Coordinate {
+        x: from.x.checked_add(dx / denominator)?,
+        y: from.y.checked_add(dy / denominator)?,
+        z: from.z.checked_add(dz / denominator)?,
+    })
+}
 
-    let second = Coordinate {
-        x: rhs.x + dx,
-        y: rhs.y + dy,
-    };
+fn get_ratio_antinodes(
+    map: &Map,
+    lhs: Coordinate,
+    rhs: Coordinate,
+    k: i32,
+    interior: bool,
+) -> HashSet<Coordinate> {
+    assert!(k >= 1, "Distance ratio must be at least 1");
 
-    [first, second]
+    // Outside the pair such a point lies `1 / (k - 1)` of the antenna distance beyond the
+    // nearer antenna, between them it lies `1 / (k + 1)` of the distance from the nearer one
+    let mut candidates = vec![
+        lattice_point(rhs, lhs, k, k - 1),
+        lattice_point(lhs, rhs, k, k - 1),
+    ];
+    if let Some(denominator) = k.checked_add(1).filter(|_| interior) {
+        candidates.push(lattice_point(lhs, rhs, 1, denominator));
+        candidates.push(lattice_point(rhs, lhs, 1, denominator));
+    }
+
+    candidates
         .into_iter()
+        .flatten()
         .filter(|pos| map.is_in_bound(pos))
         .collect()
 }
 
-fn get_all_antinodes(map: &Map, lhs: Coordinate, rhs: Coordinate) -> HashSet<Coordinate> {
+fn get_all_antinodes(
+    map: &Map,
+    lhs: Coordinate,
+    rhs: Coordinate,
+    stepping: Stepping,
+    k: i32,
+) -> HashSet<Coordinate> {
+    assert!(k >= 1, "Distance ratio must be at least 1");
     let mut antinodes = HashSet::new();
 
     // Calculate direction vector
-    let dx = rhs.x - lhs.x;
-    let dy = rhs.y - lhs.y;
-
-    // Forward antinodes (beyond rhs)
-    let mut current = rhs;
-    while map.is_in_bound(&current) {
-        antinodes.insert(current);
-        current = Coordinate {
-            x: current.x + dx,
-            y: current.y + dy,
-        };
-    }
+    let mut dx = rhs.x - lhs.x;
+    let mut dy = rhs.y - lhs.y;
+    let mut dz = rhs.z - lhs.z;
+
+    // A grid position `t / (k - 1)` of the distance away needs `t` to be a multiple of
+    // `(k - 1) / gcd(k - 1, g)`, so the step is the distance divided by `gcd(k - 1, g)`
+    let reduced = gcd(gcd(dx, dy), dz);
+    let divisor = match stepping {
+        Stepping::Raw => gcd(k - 1, reduced),
+        Stepping::Reduced => reduced,
+    };
+    dx /= divisor;
+    dy /= divisor;
+    dz /= divisor;
 
-    // Backward antinodes (before lhs)
-    current = lhs;
-    while map.is_in_bound(&current) {
-        antinodes.insert(current);
-        current = Coordinate {
-            x: current.x - dx,
-            y: current.y - dy,
+    // Steps from lhs that stay inside the box around the region, the antennas may lie outside it
+    let (min, max) = map.bounds;
+    let (first, last) = [
+        step_range(lhs.x, dx, min.x, max.x),
+        step_range(lhs.y, dy, min.y, max.y),
+        step_range(lhs.z, dz, min.z, max.z),
+    ]
+    .into_iter()
+    .fold((i32::MIN, i32::MAX), |(first, last), (from, to)| {
+        (first.max(from), last.min(to))
+    });
+
+    for step in first..=last {
+        let current = Coordinate {
+            x: lhs.x + step * dx,
+            y: lhs.y + step * dy,
+            z: lhs.z + step * dz,
         };
+        if map.is_in_bound(&current) {
+            antinodes.insert(current);
+        }
     }
 
     antinodes
 }
 
+/// Range of `t` for which `start + t * step` lies in `min..max`, empty when `first > last`
+fn step_range(start: i32, step: i32, min: i32, max: i32) -> (i32, i32) {
+    match step.cmp(&0) {
+        Ordering::Equal if (min..max).contains(&start) => (i32::MIN, i32::MAX),
+        Ordering::Equal => (0, -1),
+        Ordering::Greater => (
+            Integer::div_ceil(&(min - start), &step),
+            Integer::div_floor(&(max - 1 - start), &step),
+        ),
+        Ordering::Less => (
+            Integer::div_ceil(&(max - 1 - start), &step),
+            Integer::div_floor(&(min - start), &step),
+        ),
+    }
+}
+
 fn find_all_antinodes<F>(
-    coordinates: &Vec<Coordinate>,
+    coordinates: &[Coordinate],
     map: &Map,
     generator: &F,
 ) -> HashSet<Coordinate>
@@ -128,28 +387,264 @@ where
         .collect::<HashSet<_>>()
 }
 
-fn count_all_antinodes(map: &Map) -> usize {
+fn count_antinodes(map: &Map, rule: AntinodeRule) -> usize {
     map.antennas
-        .iter()
-        .flat_map(|(_, coordinates)| find_all_antinodes(coordinates, map, &get_first_antinodes))
+        .values()
+        .flat_map(|coordinates| {
+            find_all_antinodes(coordinates, map, &|map, lhs, rhs| {
+                rule.antinodes(map, lhs, rhs)
+            })
+        })
         .collect::<HashSet<_>>()
         .len()
 }
 
-fn count_all_antinodes_with_resonant_harmonics(map: &Map) -> usize {
-    map.antennas
+fn find_frequency_antinodes(map: &Map, frequency: char, rule: AntinodeRule) -> HashSet<Coordinate> {
+    find_all_antinodes(map.antennas_of(frequency), map, &|map, lhs, rhs| {
+        rule.antinodes(map, lhs, rhs)
+    })
+}
+
+/// Draw the map with `#` on every empty cell holding an antinode and blanks outside the region,
+/// covering both the parsed field and the region. Slices are separated by blank lines.
+/// With a frequency given, only that frequency's antennas and antinodes are shown.
+fn render(map: &Map, rule: AntinodeRule, frequency: Option<char>) -> String {
+    let shown = match frequency {
+        Some(frequency) => vec![frequency],
+        None => map.frequencies(),
+    };
+    let antinodes = shown
         .iter()
-        .flat_map(|(_, coordinates)| find_all_antinodes(coordinates, map, &get_all_antinodes))
-        .collect::<HashSet<_>>()
-        .len()
+        .flat_map(|&frequency| find_frequency_antinodes(map, frequency, rule))
+        .collect::<HashSet<_>>();
+
+    let (min, max) = map.bounds;
+    let field: (i32, i32, i32) = (
+        map.layers[0][0].len() as i32,
+        map.layers[0].len() as i32,
+        map.layers.len() as i32,
+    );
+
+    // Only slices of the parsed field or holding antinodes, a ball around a flat map
+    // would otherwise add empty slices above and below it
+    let layers = antinodes
+        .iter()
+        .fold((0, field.2), |(first, last), position| {
+            (first.min(position.z), last.max(position.z + 1))
+        });
+
+    let mut slices = Vec::new();
+    for z in layers.0..layers.1 {
+        let mut text = String::new();
+        for y in min.y.min(0)..max.y.max(field.1) {
+            for x in min.x.min(0)..max.x.max(field.0) {
+                let position = Coordinate { x, y, z };
+                let symbol = match map.cell(&position) {
+                    Some(cell) if shown.contains(&cell) => cell,
+                    _ if antinodes.contains(&position) => '#',
+                    _ if map.is_in_bound(&position) => '.',
+                    _ => ' ',
+                };
+                text.push(symbol);
+            }
+            text.push('\n');
+        }
+        slices.push(text);
+    }
+    slices.join("\n")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FrequencyStats {
+    frequency: char,
+    antennas: usize,
+    antinodes: usize,
+    /// Antinodes this frequency shares with at least one other frequency
+    overlap: usize,
+}
+
+fn frequency_stats(map: &Map, rule: AntinodeRule) -> Vec<FrequencyStats> {
+    let antinodes = map
+        .frequencies()
+        .into_iter()
+        .map(|frequency| (frequency, find_frequency_antinodes(map, frequency, rule)))
+        .collect::<Vec<_>>();
+
+    antinodes
+        .iter()
+        .map(|(frequency, own)| FrequencyStats {
+            frequency: *frequency,
+            antennas: map.antennas_of(*frequency).len(),
+            antinodes: own.len(),
+            overlap: own
+                .iter()
+                .filter(|position| {
+                    antinodes
+                        .iter()
+                        .any(|(other, theirs)| other != frequency && theirs.contains(position))
+                })
+                .count(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AntennaPair {
+    frequency: char,
+    lhs: Coordinate,
+    rhs: Coordinate,
+}
+
+/// Every pair of same-frequency antennas that creates an antinode at the given cell
+fn find_antenna_pairs(map: &Map, rule: AntinodeRule, cell: Coordinate) -> Vec<AntennaPair> {
+    let mut pairs = Vec::new();
+    for frequency in map.frequencies() {
+        let coordinates = map.antennas_of(frequency);
+        for (i, &lhs) in coordinates.iter().enumerate() {
+            for &rhs in &coordinates[i + 1..] {
+                if rule.antinodes(map, lhs, rhs).contains(&cell) {
+                    pairs.push(AntennaPair {
+                        frequency,
+                        lhs,
+                        rhs,
+                    });
+                }
+            }
+        }
+    }
+    pairs
+}
+
+/// Parse `x,y` or `x,y,z`
+fn parse_coordinate(text: &str) -> Option<Coordinate> {
+    let parts = text
+        .split(',')
+        .map(|part| part.trim().parse::<i32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [x, y] => Some(Coordinate { x, y, z: 0 }),
+        [x, y, z] => Some(Coordinate { x, y, z }),
+        _ => None,
+    }
 }
 
 fn main() -> io::Result<()> {
-    let file_path = Path::new("input.data");
-    let antennas_map = Map::load(file_path)?;
+    let args = env::args().collect::<Vec<_>>();
+    let option = |name: &str| {
+        let index = args.iter().position(|arg| arg == name)?;
+        Some(args.get(index + 1).map(String::as_str).unwrap_or_default())
+    };
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+
+    // `--input <path>` reads another map, slices separated by blank lines make a 3D field
+    let file_path = Path::new(option("--input").unwrap_or("input.data"));
+    let mut antennas_map = Map::load(file_path)?;
+
+    // `--bounds <min>:<max>` limits antinodes to a box, corners given as `x,y` or `x,y,z`
+    if let Some(bounds) = option("--bounds") {
+        let (min, max) = bounds
+            .split_once(':')
+            .and_then(|(min, max)| Some((parse_coordinate(min)?, parse_coordinate(max)?)))
+            .ok_or_else(|| invalid("--bounds expects <min>:<max>"))?;
+        // Flat corners span every slice
+        let max = match bounds.matches(',').count() {
+            2 => Coordinate {
+                z: antennas_map.bounds.1.z,
+                ..max
+            },
+            _ => max,
+        };
+        antennas_map = antennas_map
+            .with_region(Region::Box { min, max })
+            .ok_or_else(|| invalid("--bounds reaches past the coordinates the map can hold"))?;
+    }
+
+    // `--radius <r>` limits antinodes to a ball around the centre of the parsed field
+    if let Some(radius) = option("--radius") {
+        let radius = radius
+            .parse::<i32>()
+            .ok()
+            .filter(|&radius| radius >= 0)
+            .ok_or_else(|| invalid("--radius expects an integer >= 0"))?;
+        let (min, max) = antennas_map.bounds;
+        let center = Coordinate {
+            x: (min.x + max.x) / 2,
+            y: (min.y + max.y) / 2,
+            z: (min.z + max.z) / 2,
+        };
+        antennas_map = antennas_map
+            .with_region(Region::Radius { center, radius })
+            .ok_or_else(|| invalid("--radius reaches past the coordinates the map can hold"))?;
+    }
+
+    // `--mask <path>` limits antinodes to the cells marked `#` in another file
+    if let Some(mask_path) = option("--mask") {
+        let mut mask = String::new();
+        File::open(mask_path)?.read_to_string(&mut mask)?;
+        antennas_map = antennas_map
+            .with_region(Region::parse_mask(&mask))
+            .ok_or_else(|| invalid("--mask reaches past the coordinates the map can hold"))?;
+    }
+
+    // `--ratio <k>` places antinodes where one antenna is `k` times as far as the other,
+    // `--interior` also places them between the antennas. Harmonics repeat the same spacing
+    let ratio = match option("--ratio") {
+        Some(value) => value
+            .parse::<i32>()
+            .ok()
+            .filter(|&k| k >= 1)
+            .ok_or_else(|| invalid("--ratio expects an integer >= 1"))?,
+        None => 2,
+    };
+    let ratio_rule = if args.iter().any(|arg| arg == "--interior") {
+        AntinodeRule::RatioWithin(ratio)
+    } else {
+        AntinodeRule::Ratio(ratio)
+    };
+
+    // `--reduced` walks the line by the gcd-reduced step instead of the antenna distance
+    let stepping = if args.iter().any(|arg| arg == "--reduced") {
+        Stepping::Reduced
+    } else {
+        Stepping::Raw
+    };
+
+    // `--harmonics` renders and analyses the resonant harmonics rule instead of the ratio rule
+    let rule = if args.iter().any(|arg| arg == "--harmonics") {
+        AntinodeRule::Line(stepping, ratio)
+    } else {
+        ratio_rule
+    };
+
+    // `--render [frequency]` draws the antinodes, optionally for one frequency only
+    if let Some(frequency) = option("--render") {
+        let frequency = frequency
+            .chars()
+            .next()
+            .filter(|_| !frequency.starts_with("--"));
+        print!("{}", render(&antennas_map, rule, frequency));
+    }
+
+    // `--stats` prints antenna and antinode counts per frequency
+    if args.iter().any(|arg| arg == "--stats") {
+        for stats in frequency_stats(&antennas_map, rule) {
+            println!(
+                "{}: {} antennas, {} antinodes, {} shared with other frequencies",
+                stats.frequency, stats.antennas, stats.antinodes, stats.overlap
+            );
+        }
+    }
+
+    // `--query <x>,<y>[,<z>]` lists the antenna pairs creating an antinode at that cell
+    if let Some(cell) = option("--query") {
+        let cell = parse_coordinate(cell).ok_or_else(|| invalid("--query expects x,y[,z]"))?;
+        for pair in find_antenna_pairs(&antennas_map, rule, cell) {
+            println!("{}: {:?} and {:?}", pair.frequency, pair.lhs, pair.rhs);
+        }
+    }
 
     let timer = Instant::now();
-    let antinode_count = count_all_antinodes(&antennas_map);
+    let antinode_count = count_antinodes(&antennas_map, ratio_rule);
     println!(
         "There are {} antinodes within the bounds of the map",
         antinode_count
@@ -157,7 +652,7 @@ fn main() -> io::Result<()> {
     println!("Time elapsed: {:?}", timer.elapsed());
 
     let timer = Instant::now();
-    let antinode_count = count_all_antinodes_with_resonant_harmonics(&antennas_map);
+    let antinode_count = count_antinodes(&antennas_map, AntinodeRule::Line(stepping, ratio));
     println!(
         "There are {} antinodes within the bounds of the map if resonant harmonics takes",
         antinode_count
@@ -166,3 +661,198 @@ fn main() -> io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        count_antinodes, find_antenna_pairs, frequency_stats, render, AntennaPair, AntinodeRule,
+        Coordinate, FrequencyStats, Map, Region, Stepping,
+    };
+
+    #[test]
+    fn test_example_antinodes() {
+        let map = Map::parse(include_str!("test.data"));
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(2)), 14);
+        assert_eq!(
+            count_antinodes(&map, AntinodeRule::Line(Stepping::Raw, 2)),
+            34
+        );
+        assert_eq!(
+            count_antinodes(&map, AntinodeRule::Line(Stepping::Reduced, 2)),
+            34
+        );
+    }
+
+    #[test]
+    fn test_reduced_stepping() {
+        // The antennas are two columns and four rows apart, so the raw step skips a row
+        let map = Map::parse("a..\n...\n...\n...\n..a\n...\n...\n");
+        assert_eq!(
+            count_antinodes(&map, AntinodeRule::Line(Stepping::Raw, 2)),
+            2
+        );
+        assert_eq!(
+            count_antinodes(&map, AntinodeRule::Line(Stepping::Reduced, 2)),
+            3
+        );
+    }
+
+    #[test]
+    fn test_distance_ratios() {
+        let map = Map::parse("a.....a......");
+        // Only the midpoint has both antennas equally far away
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(1)), 0);
+        assert_eq!(count_antinodes(&map, AntinodeRule::RatioWithin(1)), 1);
+        // One beyond the right antenna, plus two positions between the antennas
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(2)), 1);
+        assert_eq!(count_antinodes(&map, AntinodeRule::RatioWithin(2)), 3);
+        // Two columns beyond each antenna, only the right one is on the map
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(4)), 1);
+        // One column away from each antenna towards the other
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(5)), 0);
+        assert_eq!(count_antinodes(&map, AntinodeRule::RatioWithin(5)), 2);
+        // Harmonics repeat the spacing of the antinodes, as far as the grid allows
+        let harmonics = |stepping, k| count_antinodes(&map, AntinodeRule::Line(stepping, k));
+        assert_eq!(harmonics(Stepping::Raw, 2), 3);
+        assert_eq!(harmonics(Stepping::Raw, 3), 5);
+        assert_eq!(harmonics(Stepping::Raw, 4), 7);
+        assert_eq!(harmonics(Stepping::Raw, 5), 5);
+        assert_eq!(harmonics(Stepping::Raw, 1), 13);
+        assert_eq!(harmonics(Stepping::Reduced, 3), 13);
+        // Ratios too large to compute positions for have no antinodes instead of overflowing
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(i32::MAX)), 0);
+        assert_eq!(
+            count_antinodes(&map, AntinodeRule::RatioWithin(i32::MAX)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let map = Map::parse(include_str!("test.data"));
+        let expected = [
+            "......#....#",
+            "...#....0...",
+            "....#0....#.",
+            "..#....0....",
+            "....0....#..",
+            ".#....A.....",
+            "...#........",
+            "#......#....",
+            "........A...",
+            ".........A..",
+            "..........#.",
+            "..........#.",
+        ];
+        let rendered = render(&map, AntinodeRule::Ratio(2), None);
+        assert_eq!(rendered.lines().collect::<Vec<_>>(), expected);
+
+        let only_a = render(&map, AntinodeRule::Ratio(2), Some('A'));
+        assert_eq!(only_a.matches('#').count(), 5);
+        assert!(!only_a.contains('0'));
+    }
+
+    #[test]
+    fn test_frequency_stats() {
+        let map = Map::parse(include_str!("test.data"));
+        let stats = frequency_stats(&map, AntinodeRule::Ratio(2));
+        let expected = [
+            FrequencyStats {
+                frequency: '0',
+                antennas: 4,
+                antinodes: 10,
+                overlap: 1,
+            },
+            FrequencyStats {
+                frequency: 'A',
+                antennas: 3,
+                antinodes: 5,
+                overlap: 1,
+            },
+        ];
+        assert_eq!(stats, expected);
+
+        // The antinode shared by both frequencies, hidden under the top `A` antenna
+        let pairs = find_antenna_pairs(
+            &map,
+            AntinodeRule::Ratio(2),
+            Coordinate { x: 6, y: 5, z: 0 },
+        );
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(
+            pairs[0],
+            AntennaPair {
+                frequency: '0',
+                lhs: Coordinate { x: 8, y: 1, z: 0 },
+                rhs: Coordinate { x: 7, y: 3, z: 0 },
+            }
+        );
+    }
+
+    #[test]
+    fn test_regions() {
+        let example = include_str!("test.data");
+        let rule = AntinodeRule::Ratio(2);
+
+        // A mask covering the whole field changes nothing
+        let full = example.replace(|cell: char| cell != '\n', "#");
+        let map = Map::parse(example)
+            .with_region(Region::parse_mask(&full))
+            .unwrap();
+        assert_eq!(count_antinodes(&map, rule), 14);
+
+        // Growing the field uncovers antinodes that fell off the map
+        let min = Coordinate { x: -4, y: -4, z: 0 };
+        let max = Coordinate { x: 16, y: 16, z: 1 };
+        let map = Map::parse(example)
+            .with_region(Region::Box { min, max })
+            .unwrap();
+        assert_eq!(count_antinodes(&map, rule), 17);
+
+        // Offset from the origin, harmonic lines still reach it from antennas outside
+        let min = Coordinate { x: 12, y: 0, z: 0 };
+        let max = Coordinate { x: 20, y: 12, z: 1 };
+        let map = Map::parse(example)
+            .with_region(Region::Box { min, max })
+            .unwrap();
+        let antinodes = count_antinodes(&map, AntinodeRule::Line(Stepping::Raw, 2));
+        assert_eq!(antinodes, 6);
+
+        let center = Coordinate { x: 6, y: 6, z: 0 };
+        let map = Map::parse(example)
+            .with_region(Region::Radius { center, radius: 3 })
+            .unwrap();
+        let rendered = render(&map, rule, None);
+        assert_eq!(rendered.lines().nth(2), Some("     0      "));
+        assert_eq!(rendered.lines().nth(6), Some("   #......  "));
+
+        // A ball reaching past the largest coordinate is refused instead of overflowing
+        let radius = i32::MAX - 3;
+        let map = Map::parse(example).with_region(Region::Radius { center, radius });
+        assert!(map.is_none());
+    }
+
+    #[test]
+    fn test_layered_field() {
+        let map = Map::parse("a..\n...\n...\n\n...\n.a.\n...\n\n...\n...\n...\n");
+        assert_eq!(count_antinodes(&map, AntinodeRule::Ratio(2)), 1);
+        assert_eq!(
+            count_antinodes(&map, AntinodeRule::Line(Stepping::Raw, 2)),
+            3
+        );
+
+        let pairs = find_antenna_pairs(
+            &map,
+            AntinodeRule::Ratio(2),
+            Coordinate { x: 2, y: 2, z: 2 },
+        );
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(format!("{:?}", pairs[0].rhs), "(1, 1, 1)");
+
+        let rendered = render(&map, AntinodeRule::Ratio(2), None);
+        assert_eq!(
+            rendered,
+            "a..\n...\n...\n\n...\n.a.\n...\n\n...\n...\n..#\n"
+        );
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::BTreeSet;
+use std::fmt;
 use std::time::Instant;
-use std::{io, num::ParseIntError, str::FromStr};
+use std::{env, io, str::FromStr};
 
 #[derive(Debug, Clone, Copy)]
 struct Block {
@@ -13,119 +15,423 @@ struct Disk {
     blocks: Vec<Block>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DiskError {
+    /// A character other than a decimal digit in the dense format, `position` counts characters
+    InvalidDigit { position: usize, character: char },
+    /// The dense format gives the n-th file id n, so files must stay in id order
+    OutOfOrder { expected: u64, found: u64 },
+    /// The dense format only holds runs of up to 9 blocks
+    RunTooLong { id: u64, len: u64 },
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiskError::InvalidDigit {
+                position,
+                character,
+            } => write!(
+                f,
+                "invalid character {:?} at position {} of the disk map",
+                character, position
+            ),
+            DiskError::OutOfOrder { expected, found } => write!(
+                f,
+                "file {} is where file {} should be, the dense format needs files in id order",
+                found, expected
+            ),
+            DiskError::RunTooLong { id, len } => write!(
+                f,
+                "run of {} blocks at file {} does not fit a single digit",
+                len, id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {}
+
+impl From<DiskError> for io::Error {
+    fn from(error: DiskError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
 impl FromStr for Disk {
-    type Err = ParseIntError;
+    type Err = DiskError;
 
     fn from_str(puzzle: &str) -> Result<Self, Self::Err> {
-        let blocks = puzzle
+        let digits = puzzle
             .trim()
             .chars()
-            .collect::<Vec<char>>()
+            .enumerate()
+            .map(|(position, character)| {
+                character
+                    .to_digit(10)
+                    .map(u64::from)
+                    .ok_or(DiskError::InvalidDigit {
+                        position,
+                        character,
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let blocks = digits
             .chunks(2)
             .enumerate()
-            .map(|(id, chunk)| {
-                let used = chunk[0].to_string().parse()?;
-                let free = chunk.get(1).unwrap_or(&'0').to_string().parse()?;
-                Ok(Block {
-                    id: id as u64,
-                    used,
-                    free,
-                })
+            .map(|(id, chunk)| Block {
+                id: id as u64,
+                used: chunk[0],
+                free: chunk.get(1).copied().unwrap_or(0),
             })
-            .collect::<Result<Vec<_>, ParseIntError>>()?;
+            .collect();
 
         Ok(Disk { blocks })
     }
 }
 
+/// Where a file goes when it is moved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    /// Leftmost gap that fits, the puzzle policy
+    First,
+    /// Smallest gap that fits, leftmost among equals
+    Best,
+    /// Largest gap, leftmost among equals
+    Worst,
+    /// First gap that fits after the previous placement, wrapping around to the start
+    Next,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Policy {
+    /// Move single blocks from the end into the leftmost free block
+    Blocks,
+    /// Move whole files into gaps on their left
+    Files(Placement),
+    /// Make every file contiguous and all free space one run at the end
+    Defragment,
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Blocks => write!(f, "block"),
+            Policy::Files(Placement::First) => write!(f, "file (first fit)"),
+            Policy::Files(Placement::Best) => write!(f, "file (best fit)"),
+            Policy::Files(Placement::Worst) => write!(f, "file (worst fit)"),
+            Policy::Files(Placement::Next) => write!(f, "file (next fit)"),
+            Policy::Defragment => write!(f, "full defragment"),
+        }
+    }
+}
+
+/// What a compaction did and how fragmented it left the free space
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Metrics {
+    /// Files with at least one block in a new place
+    files_moved: usize,
+    blocks_moved: u64,
+    free_fragments: usize,
+    largest_free_run: u64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files moved, {} blocks moved, {} free fragments, largest free run {}",
+            self.files_moved, self.blocks_moved, self.free_fragments, self.largest_free_run
+        )
+    }
+}
+
+/// Gap offsets ordered per gap size, so every placement policy is a few range lookups
+struct GapIndex {
+    by_size: Vec<BTreeSet<u64>>,
+}
+
+impl GapIndex {
+    fn new(free_runs: &[(u64, u64)]) -> Self {
+        let largest = free_runs.iter().map(|&(_, len)| len).max().unwrap_or(0);
+        let mut index = GapIndex {
+            by_size: vec![BTreeSet::new(); largest as usize + 1],
+        };
+        for &(offset, len) in free_runs {
+            index.insert(offset, len);
+        }
+        index
+    }
+
+    fn insert(&mut self, offset: u64, len: u64) {
+        if len > 0 {
+            self.by_size[len as usize].insert(offset);
+        }
+    }
+
+    fn remove(&mut self, offset: u64, len: u64) {
+        self.by_size[len as usize].remove(&offset);
+    }
+
+    /// Gap as `(offset, size)` for a file of `len` blocks, only gaps before `limit` qualify
+    fn find(&self, placement: Placement, len: u64, limit: u64, cursor: u64) -> Option<(u64, u64)> {
+        let leftmost = |size: usize, from: u64, to: u64| {
+            self.by_size[size]
+                .range(from..to)
+                .next()
+                .map(|&offset| (offset, size as u64))
+        };
+        let mut sizes = (len as usize)..self.by_size.len();
+
+        match placement {
+            Placement::First => sizes.filter_map(|size| leftmost(size, 0, limit)).min(),
+            Placement::Best => sizes.find_map(|size| leftmost(size, 0, limit)),
+            Placement::Worst => sizes.rev().find_map(|size| leftmost(size, 0, limit)),
+            Placement::Next => sizes
+                .clone()
+                .filter_map(|size| leftmost(size, cursor.min(limit), limit))
+                .min()
+                .or_else(|| {
+                    sizes
+                        .filter_map(|size| leftmost(size, 0, cursor.min(limit)))
+                        .min()
+                }),
+        }
+    }
+}
+
+/// A file placed on the disk, `offset` is the index of its first block
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    id: u64,
+    offset: u64,
+    len: u64,
+}
+
 impl Disk {
-    fn compact_by_block_policy(&self) -> Self {
-        let mut compacted = self.blocks.clone();
-
-        let mut start = 0;
-        let mut end = compacted.len() - 1;
-        while start < end {
-            if compacted[start].free > 0 {
-                let free_space = compacted[start].free;
-                let move_amount = free_space.min(compacted[end].used);
-
-                compacted[start].free = 0;
-                let mut new_sector = Block {
-                    id: compacted[end].id,
-                    used: move_amount,
-                    free: free_space - move_amount,
+    fn len(&self) -> u64 {
+        self.blocks
+            .iter()
+            .map(|block| block.used + block.free)
+            .sum()
+    }
+
+    fn extents(&self) -> Vec<Extent> {
+        let mut offset = 0;
+        self.blocks
+            .iter()
+            .map(|block| {
+                let extent = Extent {
+                    id: block.id,
+                    offset,
+                    len: block.used,
                 };
-                compacted[end].used -= move_amount;
-
-                if compacted[end].used == 0 {
-                    let new_free_space = move_amount + compacted[end].free;
-                    if end - 1 == start {
-                        new_sector.free += new_free_space;
-                    } else {
-                        compacted[end - 1].free += new_free_space;
+                offset += block.used + block.free;
+                extent
+            })
+            .collect()
+    }
+
+    /// Rebuild a disk from files sorted by offset, the first one must start at offset 0
+    fn from_extents(extents: &[Extent], len: u64) -> Self {
+        let blocks = extents
+            .iter()
+            .enumerate()
+            .map(|(index, extent)| {
+                let next = extents.get(index + 1).map_or(len, |next| next.offset);
+                Block {
+                    id: extent.id,
+                    used: extent.len,
+                    free: next - extent.offset - extent.len,
+                }
+            })
+            .collect();
+
+        Self { blocks }
+    }
+
+    /// Free space as `(offset, len)` runs in disk order, including the free space at the end.
+    /// Gaps on both sides of an empty file are one run.
+    fn free_runs(&self) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for (block, extent) in self.blocks.iter().zip(self.extents()) {
+            match runs.last_mut() {
+                Some((offset, len)) if block.used == 0 && *offset + *len == extent.offset => {
+                    *len += block.free
+                }
+                _ => runs.push((extent.offset + extent.len, block.free)),
+            }
+        }
+        runs.retain(|&(_, len)| len > 0);
+        runs
+    }
+
+    fn compact(&self, policy: Policy) -> (Self, Metrics) {
+        let (disk, mut metrics) = match policy {
+            Policy::Blocks => self.move_blocks(),
+            Policy::Files(placement) => self.move_files(placement),
+            Policy::Defragment => self.defragment(),
+        };
+
+        let free_runs = disk.free_runs();
+        metrics.free_fragments = free_runs.len();
+        metrics.largest_free_run = free_runs.iter().map(|&(_, len)| len).max().unwrap_or(0);
+        (disk, metrics)
+    }
+
+    fn compact_by_block_policy(&self) -> Self {
+        self.compact(Policy::Blocks).0
+    }
+
+    fn compact_by_file_policy(&self) -> Self {
+        self.compact(Policy::Files(Placement::First)).0
+    }
+
+    /// Fill every gap from the left with single blocks taken from the rightmost files
+    fn move_blocks(&self) -> (Self, Metrics) {
+        let blocks = &self.blocks;
+        let mut metrics = Metrics::default();
+        let mut compacted: Vec<Block> = Vec::with_capacity(blocks.len() * 2);
+        let mut push = |id: u64, used: u64| match compacted.last_mut() {
+            Some(last) if last.id == id => last.used += used,
+            _ => compacted.push(Block { id, used, free: 0 }),
+        };
+
+        let mut tail = blocks.len() - 1;
+        let mut tail_left = blocks[tail].used;
+        let mut head = 0;
+        while head < tail {
+            push(blocks[head].id, blocks[head].used);
+
+            let mut gap = blocks[head].free;
+            while gap > 0 && head < tail {
+                let moved = gap.min(tail_left);
+                if moved > 0 {
+                    if tail_left == blocks[tail].used {
+                        metrics.files_moved += 1;
                     }
-                    compacted.remove(end);
-                } else {
-                    compacted[end].free += move_amount;
+                    metrics.blocks_moved += moved;
+                    push(blocks[tail].id, moved);
                 }
+                gap -= moved;
+                tail_left -= moved;
 
-                compacted.insert(start + 1, new_sector);
-                end = compacted.len() - 1;
+                if tail_left == 0 {
+                    tail -= 1;
+                    tail_left = blocks[tail].used;
+                }
             }
 
-            start += 1;
+            head += 1;
+        }
+
+        // The head caught up with a partially moved file
+        if head == tail {
+            push(blocks[tail].id, tail_left);
+        }
+
+        let used = compacted.iter().map(|block| block.used).sum::<u64>();
+        if let Some(last) = compacted.last_mut() {
+            last.free = self.len() - used;
         }
 
-        Self { blocks: compacted }
+        (Self { blocks: compacted }, metrics)
     }
 
-    fn compact_by_file_policy(&self) -> Self {
-        let mut optimized = self.blocks.clone();
+    /// Move every file once, from the highest id down, into a gap on its left chosen by
+    /// the placement policy. Files only ever move left, so the space they leave behind
+    /// is never reused: every file that could still move into it lies further left.
+    fn move_files(&self, placement: Placement) -> (Self, Metrics) {
+        let mut extents = self.extents();
+        let mut metrics = Metrics::default();
+        let mut gaps = GapIndex::new(&self.free_runs());
+        let mut cursor = 0;
 
-        let file_ids: Vec<u64> = optimized
-            .iter()
-            .skip(1) // we never move first file
-            .map(|sector| sector.id)
-            .rev()
-            .collect();
+        for extent in extents.iter_mut().skip(1).rev() {
+            if extent.len == 0 {
+                continue;
+            }
 
-        for file_id in file_ids {
-            let file_index = optimized
-                .iter()
-                .position(|sector| sector.id == file_id)
-                .expect("file not found");
+            if let Some((offset, size)) = gaps.find(placement, extent.len, extent.offset, cursor) {
+                gaps.remove(offset, size);
+                gaps.insert(offset + extent.len, size - extent.len);
+                extent.offset = offset;
+                cursor = offset + extent.len;
 
-            let file_size = optimized[file_index].used;
+                metrics.files_moved += 1;
+                metrics.blocks_moved += extent.len;
+            }
+        }
 
-            let fit_index = optimized
-                .iter()
-                .enumerate()
-                .find(|(index, sector)| index < &file_index && sector.free >= file_size)
-                .map(|(index, _)| index);
+        // An empty file may now sit inside a file moved into the gap around it,
+        // push it to the end of that file
+        extents.sort_unstable_by_key(|extent| (extent.offset, extent.len));
+        let mut end = 0;
+        for extent in &mut extents {
+            extent.offset = extent.offset.max(end);
+            end = extent.offset + extent.len;
+        }
+        (Self::from_extents(&extents, self.len()), metrics)
+    }
 
-            if let Some(target_index) = fit_index {
-                let free_space = optimized[target_index].free - file_size;
-                optimized[target_index].free = 0;
+    /// Pack every file to the left in disk order, leaving all free space at the end
+    fn defragment(&self) -> (Self, Metrics) {
+        let mut extents = self.extents();
+        let mut metrics = Metrics::default();
 
-                let mut new_block = Block {
-                    id: file_id,
-                    used: file_size,
-                    free: free_space,
-                };
+        let mut end = 0;
+        for extent in &mut extents {
+            if extent.offset != end && extent.len > 0 {
+                metrics.files_moved += 1;
+                metrics.blocks_moved += extent.len;
+            }
+            extent.offset = end;
+            end += extent.len;
+        }
+
+        (Self::from_extents(&extents, self.len()), metrics)
+    }
 
-                let sector_free_space = optimized[file_index].free;
-                if file_index - 1 == target_index {
-                    new_block.free += file_size + sector_free_space
-                } else {
-                    optimized[file_index - 1].free += file_size + sector_free_space;
+    /// The dense digit format `Disk::from_str` reads, without a trailing zero for the last gap
+    fn to_dense(&self) -> Result<String, DiskError> {
+        let mut dense = String::with_capacity(self.blocks.len() * 2);
+        for (index, block) in self.blocks.iter().enumerate() {
+            if block.id != index as u64 {
+                return Err(DiskError::OutOfOrder {
+                    expected: index as u64,
+                    found: block.id,
+                });
+            }
+
+            let is_last = index + 1 == self.blocks.len();
+            for len in [block.used, block.free] {
+                if len > 9 {
+                    return Err(DiskError::RunTooLong { id: block.id, len });
                 }
+            }
 
-                optimized.remove(file_index);
-                optimized.insert(target_index + 1, new_block);
+            dense.push(char::from(b'0' + block.used as u8));
+            if !is_last || block.free > 0 {
+                dense.push(char::from(b'0' + block.free as u8));
             }
         }
+        Ok(dense)
+    }
 
-        Self { blocks: optimized }
+    /// The puzzle's block-level view, one id per used block and `.` per free block.
+    /// Ids of more than one digit are bracketed, e.g. `(10)`, to keep blocks apart.
+    fn render(&self) -> String {
+        let mut text = String::new();
+        for block in &self.blocks {
+            let id = match block.id {
+                0..=9 => block.id.to_string(),
+                id => format!("({})", id),
+            };
+            text.push_str(&id.repeat(block.used as usize));
+            text.push_str(&".".repeat(block.free as usize));
+        }
+        text
     }
 
     fn calculate_checksum(&self) -> u64 {
@@ -143,7 +449,7 @@ impl Disk {
 
 fn main() -> io::Result<()> {
     let puzzle_input = include_str!("input.data");
-    let disk = Disk::from_str(puzzle_input).expect("could not load disk map");
+    let disk = Disk::from_str(puzzle_input)?;
 
     let timer = Instant::now();
     let checksum = disk.compact_by_block_policy().calculate_checksum();
@@ -161,12 +467,46 @@ fn main() -> io::Result<()> {
     );
     println!("Time elapsed: {:?}", timer.elapsed());
 
+    let policies = [
+        Policy::Blocks,
+        Policy::Files(Placement::First),
+        Policy::Files(Placement::Best),
+        Policy::Files(Placement::Worst),
+        Policy::Files(Placement::Next),
+        Policy::Defragment,
+    ];
+    // `--render` prints the block-level view of every compacted disk
+    let render = env::args().any(|arg| arg == "--render");
+    // `--dense` prints every compacted disk back in the dense format, where it can be expressed
+    let dense = env::args().any(|arg| arg == "--dense");
+
+    for policy in policies {
+        let timer = Instant::now();
+        let (compacted, metrics) = disk.compact(policy);
+        if render {
+            println!("{}", compacted.render());
+        }
+        if dense {
+            match compacted.to_dense() {
+                Ok(map) => println!("{}", map),
+                Err(error) => println!("No dense form: {}", error),
+            }
+        }
+        println!(
+            "Checksum by {} policy is {}: {}",
+            policy,
+            compacted.calculate_checksum(),
+            metrics
+        );
+        println!("Time elapsed: {:?}", timer.elapsed());
+    }
+
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Disk;
+    use crate::{Block, Disk, DiskError, Metrics, Placement, Policy};
     use std::str::FromStr;
 
     #[test]
@@ -186,4 +526,164 @@ mod tests {
         let disk = Disk::from_str("2333133121414131402").expect("could not load disk map");
         assert_eq!(disk.compact_by_file_policy().calculate_checksum(), 2858);
     }
+
+    #[test]
+    fn test_example_metrics() {
+        let disk = Disk::from_str("2333133121414131402").expect("could not load disk map");
+
+        // 00992111777.44.333....5555.6666.....8888..
+        let (compacted, metrics) = disk.compact(Policy::Files(Placement::First));
+        assert_eq!(compacted.calculate_checksum(), 2858);
+        let expected = Metrics {
+            files_moved: 4,
+            blocks_moved: 8,
+            free_fragments: 6,
+            largest_free_run: 5,
+        };
+        assert_eq!(metrics, expected);
+
+        // 0011122233344455556666777888899..............
+        let (_, metrics) = disk.compact(Policy::Defragment);
+        let expected = Metrics {
+            files_moved: 9,
+            blocks_moved: 26,
+            free_fragments: 1,
+            largest_free_run: 14,
+        };
+        assert_eq!(metrics, expected);
+    }
+
+    #[test]
+    fn test_placements() {
+        // 0.....1..2
+        let disk = Disk::from_str("15121").expect("could not load disk map");
+        let checksum = |placement| {
+            let (compacted, _) = disk.compact(Policy::Files(placement));
+            compacted.calculate_checksum()
+        };
+
+        // 021.......
+        assert_eq!(checksum(Placement::First), 4);
+        assert_eq!(checksum(Placement::Worst), 4);
+        assert_eq!(checksum(Placement::Next), 4);
+        // 01.....2..
+        assert_eq!(checksum(Placement::Best), 15);
+    }
+
+    #[test]
+    fn test_dense_round_trip() {
+        let map = "2333133121414131402";
+        let disk = Disk::from_str(map).expect("could not load disk map");
+        assert_eq!(disk.to_dense(), Ok(map.to_string()));
+        assert_eq!(disk.render(), "00...111...2...333.44.5555.6666.777.888899");
+
+        let compacted = disk.compact(Policy::Files(Placement::First)).0;
+        assert_eq!(
+            compacted.render(),
+            "00992111777.44.333....5555.6666.....8888.."
+        );
+        assert_eq!(
+            compacted.to_dense(),
+            Err(DiskError::OutOfOrder {
+                expected: 1,
+                found: 9
+            })
+        );
+
+        let disk = Disk::from_str("10101010101010101010102").expect("could not load disk map");
+        assert_eq!(disk.render(), "0123456789(10)(11)(11)");
+
+        let disk = Disk {
+            blocks: vec![Block {
+                id: 0,
+                used: 12,
+                free: 0,
+            }],
+        };
+        assert_eq!(
+            disk.to_dense(),
+            Err(DiskError::RunTooLong { id: 0, len: 12 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_digits() {
+        let error = Disk::from_str("2333\n1331").unwrap_err();
+        assert_eq!(
+            error,
+            DiskError::InvalidDigit {
+                position: 4,
+                character: '\n'
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "invalid character '\\n' at position 4 of the disk map"
+        );
+    }
+
+    /// Compact block by block on the expanded disk, as a slow but obvious reference
+    fn reference_checksums(disk: &Disk) -> (u64, u64) {
+        let mut cells = Vec::new();
+        for block in &disk.blocks {
+            cells.extend(std::iter::repeat_n(Some(block.id), block.used as usize));
+            cells.extend(std::iter::repeat_n(None, block.free as usize));
+        }
+        let checksum = |cells: &[Option<u64>]| {
+            cells
+                .iter()
+                .enumerate()
+                .map(|(position, id)| position as u64 * id.unwrap_or(0))
+                .sum::<u64>()
+        };
+
+        let mut by_blocks = cells.clone();
+        let (mut head, mut tail) = (0, by_blocks.len() - 1);
+        while head < tail {
+            match (by_blocks[head], by_blocks[tail]) {
+                (Some(_), _) => head += 1,
+                (_, None) => tail -= 1,
+                (None, Some(_)) => by_blocks.swap(head, tail),
+            }
+        }
+
+        let mut by_files = cells;
+        for block in disk.blocks.iter().rev().filter(|block| block.used > 0) {
+            let start = by_files
+                .iter()
+                .position(|&id| id == Some(block.id))
+                .unwrap();
+            let len = block.used as usize;
+            let target = (0..start)
+                .find(|&offset| by_files[offset..offset + len].iter().all(Option::is_none));
+            if let Some(target) = target {
+                by_files.copy_within(start..start + len, target);
+                by_files[start..start + len].fill(None);
+            }
+        }
+
+        (checksum(&by_blocks), checksum(&by_files))
+    }
+
+    #[test]
+    fn test_matches_reference_compaction() {
+        // Deterministic pseudo-random disk map, including empty files and gaps
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let map = (0..2001)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                char::from(b'0' + (state % 10) as u8)
+            })
+            .collect::<String>();
+
+        let disk = Disk::from_str(&map).expect("could not load disk map");
+        let (by_blocks, by_files) = reference_checksums(&disk);
+        assert_eq!(
+            disk.compact_by_block_policy().calculate_checksum(),
+            by_blocks
+        );
+        assert_eq!(disk.compact_by_file_policy().calculate_checksum(), by_files);
+    }
 }